@@ -0,0 +1,173 @@
+// Cookie name must match lease::CONTROLLER_COOKIE.
+const CONTROLLER_COOKIE = 'controller';
+const $ = id => document.getElementById(id);
+
+const post = path => fetch(path, { method: 'POST' })
+    .then(res => res.text())
+    .then(text => { $('response').innerText = text; return text; });
+
+// Controls
+
+const name = document.cookie.split('; ').find(cookie => cookie.startsWith(CONTROLLER_COOKIE + '='));
+if (name) { $('controller').value = name.split('=')[1]; }
+$('controller').onchange = e => {
+    document.cookie = CONTROLLER_COOKIE + '=' + e.target.value + '; path=/; SameSite=Strict';
+};
+$('start').onclick = () => post('/start');
+$('stop').onclick = () => post('/stop');
+$('takeover').onclick = () => post('/api/lease/takeover');
+
+const setState = state => {
+    $('banner').innerText = state;
+    $('banner').className = 'banner ' + state.split(':')[0];
+};
+
+// Heartbeats are only needed while the car is running, and only count from the lease holder,
+// so other open dashboards can't keep the car alive once its controller is gone.
+let holding = false;
+setInterval(() => fetch('/api/status')
+    .then(res => res.text())
+    .then(state => {
+        setState(state);
+        if (state == 'enabled' && holding) { fetch('/api/heartbeat', { method: 'POST' }); }
+    }), 250);
+setInterval(() => fetch('/api/lease')
+    .then(res => res.text())
+    .then(text => {
+        $('holder').innerText = text;
+        // Must match the message of lease::lease_status.
+        holding = text.startsWith('Controlled by ' + $('controller').value + ' (');
+    }), 1000);
+
+// Camera preview and ROI editor
+
+const canvas = $('roi');
+const ctx = canvas.getContext('2d');
+const frame = new Image();
+let roi = null;
+let dragStart = null;
+
+const drawFrame = () => {
+    ctx.drawImage(frame, 0, 0);
+    if (roi) {
+        ctx.strokeStyle = 'red';
+        ctx.lineWidth = 2;
+        ctx.strokeRect(roi.x, roi.y, roi.width, roi.height);
+    }
+};
+const refreshFrame = () => { frame.src = '/api/frame.jpg?' + Date.now(); };
+frame.onload = () => {
+    if (canvas.width != frame.width) { canvas.width = frame.width; }
+    if (canvas.height != frame.height) { canvas.height = frame.height; }
+    drawFrame();
+    if ($('preview').checked) { setTimeout(refreshFrame, 200); }
+};
+frame.onerror = () => setTimeout(refreshFrame, 1000);
+$('preview').onchange = e => { if (e.target.checked) { refreshFrame(); } };
+fetch('/api/roi').then(res => res.json()).then(current => { roi = current; refreshFrame(); });
+
+const point = e => {
+    const rect = canvas.getBoundingClientRect();
+    return [Math.round(e.clientX - rect.left), Math.round(e.clientY - rect.top)];
+};
+canvas.onmousedown = e => { dragStart = point(e); };
+canvas.onmousemove = e => {
+    if (!dragStart) { return; }
+    const [x, y] = point(e);
+    roi = {
+        x: Math.min(x, dragStart[0]), y: Math.min(y, dragStart[1]),
+        width: Math.abs(x - dragStart[0]), height: Math.abs(y - dragStart[1]),
+    };
+    drawFrame();
+};
+canvas.onmouseup = () => {
+    dragStart = null;
+    fetch(`/api/roi?x=${roi.x}&y=${roi.y}&width=${roi.width}&height=${roi.height}`, { method: 'POST' })
+        .then(res => res.text())
+        .then(text => { $('roi-status').innerText = text; });
+};
+
+// Telemetry plots
+
+class Plot {
+    constructor(canvas, min, max, colours) {
+        this.ctx = canvas.getContext('2d');
+        this.width = canvas.width;
+        this.height = canvas.height;
+        this.min = min;
+        this.max = max;
+        this.colours = colours;
+        this.series = colours.map(() => []);
+    }
+
+    push(...vals) {
+        vals.forEach((val, i) => {
+            this.series[i].push(val);
+            if (this.series[i].length > this.width) { this.series[i].shift(); }
+        });
+        this.draw();
+    }
+
+    y(val) {
+        return this.height - ((val - this.min) / (this.max - this.min)) * this.height;
+    }
+
+    draw() {
+        this.ctx.clearRect(0, 0, this.width, this.height);
+        this.ctx.strokeStyle = 'lightgrey';
+        this.ctx.beginPath();
+        this.ctx.moveTo(0, this.y((this.min + this.max) / 2));
+        this.ctx.lineTo(this.width, this.y((this.min + this.max) / 2));
+        this.ctx.stroke();
+        this.series.forEach((vals, i) => {
+            this.ctx.strokeStyle = this.colours[i];
+            this.ctx.beginPath();
+            vals.forEach((val, x) => x == 0 ? this.ctx.moveTo(x, this.y(val)) : this.ctx.lineTo(x, this.y(val)));
+            this.ctx.stroke();
+        });
+    }
+}
+
+const anglePlot = new Plot($('angle-plot'), -90, 90, ['black']);
+const dutyPlot = new Plot($('duty-plot'), 0, 0.12, ['red', 'blue']);
+
+// Event stream
+
+const log = $('log');
+const append = msg => {
+    log.textContent += msg.data + '\n';
+    log.scrollTop = log.scrollHeight;
+};
+const events = new EventSource('/api/events');
+events.addEventListener('log', append);
+// Transitions are logged too, so state events only update the status.
+events.addEventListener('state', msg => setState(msg.data));
+events.addEventListener('telemetry', msg => {
+    const telemetry = JSON.parse(msg.data);
+    anglePlot.push(telemetry.angle);
+    dutyPlot.push(telemetry.left_duty_cycle, telemetry.right_duty_cycle);
+});
+
+// Config
+
+const loadConfig = () => {
+    fetch('/api/config').then(res => res.text()).then(text => { $('config').textContent = text; });
+    fetch('/api/profiles').then(res => res.json()).then(({ active, profiles }) => {
+        $('profile').innerHTML = '';
+        profiles.forEach(name => $('profile').append(new Option(name, name, false, name === active)));
+    });
+    fetch('/api/config/history').then(res => res.text()).then(text => {
+        $('history').innerHTML = '';
+        text.split('\n').filter(line => line).reverse().forEach(timestamp => {
+            const item = document.createElement('li');
+            const restore = document.createElement('button');
+            restore.innerText = 'restore';
+            restore.onclick = () => post('/api/config/restore/' + timestamp).then(loadConfig);
+            item.append(new Date(Number(timestamp)).toLocaleString() + ' ', restore);
+            $('history').append(item);
+        });
+    });
+};
+$('profile').onchange = () => post('/api/profile/' + encodeURIComponent($('profile').value)).then(loadConfig);
+$('save').onclick = () => post('/api/config/save').then(loadConfig);
+loadConfig();
@@ -0,0 +1,287 @@
+//! Nudges thresholds to follow the lighting, see the [adaptive] table of thresholds.toml.
+//!
+//! Each range follows the mean colour of the pixels it confidently detects,
+//! those still detected after eroding its mask, as a moving average over recent frames.
+//! The range is shifted by how far that mean has moved since the range was configured,
+//! up to max_drift on each channel, so it can never wander far from what was set.
+//! Tracking starts again whenever the configured range changes.
+
+use std::collections::HashMap;
+
+use opencv::core::{bitwise_and, count_non_zero, mean, Mat, Point, Size, BORDER_CONSTANT};
+use opencv::imgproc::{
+    erode, get_structuring_element, morphology_default_border_value, MORPH_RECT,
+};
+use opencv::prelude::*;
+use serde::Deserialize;
+
+use crate::config::{self, ConfigError, Field};
+use crate::path::{self, ColourRange, ColourSpace, Thresholds};
+
+/// Number of hues, as OpenCV stores them.
+const HUES: f64 = 180.0;
+
+/// Models the [adaptive] table of the config.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AdaptiveConfig {
+    /// Weight of each frame in the moving average, 0 to 1.
+    pub rate: f64,
+    /// Furthest a range may shift on each channel.
+    pub max_drift: [u8; 3],
+    /// Fewest confident pixels a frame needs to count towards the average.
+    pub min_pixels: i32,
+}
+
+/// Keys of the [adaptive] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct AdaptiveSchema {
+    rate: Field<f64>,
+    max_drift: Field<[i64; 3]>,
+    min_pixels: Field<i64>,
+}
+
+impl AdaptiveConfig {
+    /// Returns None if there is no [adaptive] table, i.e. thresholds are used as configured.
+    pub fn from_toml(path: &str) -> Result<Option<Self>, Vec<ConfigError>> {
+        config::section(path, "adaptive", |schema: AdaptiveSchema, checker| {
+            let rate = match checker.get("adaptive.rate", schema.rate) {
+                Some((rate, _)) if rate > 0.0 && rate <= 1.0 => rate,
+                Some((rate, line)) => {
+                    checker.error(
+                        "adaptive.rate",
+                        Some(line),
+                        format!("must be above 0 and at most 1, got {rate}"),
+                    );
+                    0.05
+                }
+                None => 0.05,
+            };
+            let max_drift = match checker.get("adaptive.max_drift", schema.max_drift) {
+                Some((drift, _)) if drift.iter().all(|val| (0..=255).contains(val)) => {
+                    drift.map(|val| val as u8)
+                }
+                Some((drift, line)) => {
+                    checker.error(
+                        "adaptive.max_drift",
+                        Some(line),
+                        format!("must be 3 ints from 0 to 255, got {drift:?}"),
+                    );
+                    [10, 40, 40]
+                }
+                None => [10, 40, 40],
+            };
+            Self {
+                rate,
+                max_drift,
+                min_pixels: checker.int(
+                    "adaptive.min_pixels",
+                    schema.min_pixels,
+                    200,
+                    1..=i32::MAX as i64,
+                ) as i32,
+            }
+        })
+    }
+}
+
+/// Follows the colour of what one configured range detects.
+#[derive(Clone, Debug)]
+pub struct Tracker {
+    /// Range as configured, which the shift is applied to.
+    pub configured: ColourRange,
+    /// Mean colour when first seen, None until enough pixels have been.
+    reference: Option<[f64; 3]>,
+    /// Moving average of the mean colour since.
+    average: [f64; 3],
+}
+
+impl Tracker {
+    pub fn new(configured: ColourRange) -> Self {
+        Tracker {
+            configured,
+            reference: None,
+            average: [0.0; 3],
+        }
+    }
+
+    /// Adds the mean colour of a frame's confident pixels to the moving average.
+    pub fn update(&mut self, sample: [f64; 3], space: ColourSpace, rate: f64) {
+        if self.reference.is_none() {
+            self.reference = Some(sample);
+            self.average = sample;
+            return;
+        }
+        for (channel, average) in self.average.iter_mut().enumerate() {
+            *average += rate * difference(space, channel, sample[channel], *average);
+            if space == ColourSpace::Hsv && channel == 0 {
+                *average = average.rem_euclid(HUES);
+            }
+        }
+    }
+
+    /// Returns how far the range is shifted on each channel.
+    pub fn shift(&self, space: ColourSpace, max_drift: [u8; 3]) -> [i16; 3] {
+        let Some(reference) = self.reference else {
+            return [0; 3];
+        };
+        let mut shift = [0; 3];
+        for (channel, shift) in shift.iter_mut().enumerate() {
+            let max = max_drift[channel] as f64;
+            let diff = difference(space, channel, self.average[channel], reference[channel]);
+            *shift = diff.clamp(-max, max).round() as i16;
+        }
+        shift
+    }
+
+    /// Returns the configured range shifted to follow the colour.
+    /// Hues wrap around, other channels stop at their limits.
+    pub fn range(&self, space: ColourSpace, max_drift: [u8; 3]) -> ColourRange {
+        let shift = self.shift(space, max_drift);
+        let shifted = |bound: [u8; 3]| {
+            let mut shifted = [0; 3];
+            for (channel, (_, max)) in space.channels().into_iter().enumerate() {
+                let val = bound[channel] as i16 + shift[channel];
+                shifted[channel] = if space == ColourSpace::Hsv && channel == 0 {
+                    val.rem_euclid(HUES as i16) as u8
+                } else {
+                    val.clamp(0, max as i16) as u8
+                };
+            }
+            shifted
+        };
+        ColourRange {
+            lower: shifted(self.configured.lower),
+            upper: shifted(self.configured.upper),
+        }
+    }
+}
+
+/// Returns first - second on a channel, the shortest way around for hues.
+fn difference(space: ColourSpace, channel: usize, first: f64, second: f64) -> f64 {
+    let diff = first - second;
+    if space == ColourSpace::Hsv && channel == 0 {
+        (diff + HUES / 2.0).rem_euclid(HUES) - HUES / 2.0
+    } else {
+        diff
+    }
+}
+
+/// Adapts the thresholds of each class.
+pub struct Adapter {
+    config: AdaptiveConfig,
+    /// Colour space and trackers of each range, by class.
+    classes: HashMap<&'static str, (ColourSpace, Vec<Tracker>)>,
+    /// Kernel to erode masks down to confident pixels with.
+    kernel: Mat,
+}
+
+impl Adapter {
+    pub fn new(config: AdaptiveConfig) -> Self {
+        Adapter {
+            config,
+            classes: HashMap::new(),
+            kernel: get_structuring_element(MORPH_RECT, Size::new(3, 3), Point::new(-1, -1))
+                .unwrap(),
+        }
+    }
+
+    /// Returns the thresholds to detect class with,
+    /// starting its tracking again if its configured thresholds have changed.
+    pub fn thresholds(&mut self, class: &'static str, configured: &Thresholds) -> Thresholds {
+        let (space, trackers) = self
+            .classes
+            .entry(class)
+            .or_insert_with(|| (configured.space, vec![]));
+        let unchanged = *space == configured.space
+            && trackers
+                .iter()
+                .map(|tracker| tracker.configured)
+                .eq(configured.ranges.iter().copied());
+        if !unchanged {
+            *space = configured.space;
+            *trackers = configured
+                .ranges
+                .iter()
+                .copied()
+                .map(Tracker::new)
+                .collect();
+        }
+        Thresholds {
+            space: *space,
+            ranges: trackers
+                .iter()
+                .map(|tracker| tracker.range(*space, self.config.max_drift))
+                .collect(),
+        }
+    }
+
+    /// Returns how far each of class's ranges is shifted.
+    pub fn shifts(&self, class: &str) -> Vec<[i16; 3]> {
+        match self.classes.get(class) {
+            Some((space, trackers)) => trackers
+                .iter()
+                .map(|tracker| tracker.shift(*space, self.config.max_drift))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Follows the colours class detects in image, which is in the class's colour space.
+    pub fn observe(&mut self, class: &str, image: &Mat) {
+        let Some((space, trackers)) = self.classes.get_mut(class) else {
+            return;
+        };
+        for tracker in trackers {
+            let range = tracker.range(*space, self.config.max_drift);
+            let bounds = Thresholds {
+                space: *space,
+                ranges: vec![range],
+            }
+            .bounds();
+            let mut confident = Mat::default();
+            erode(
+                &path::threshold(image, &bounds),
+                &mut confident,
+                &self.kernel,
+                Point::new(-1, -1),
+                1,
+                BORDER_CONSTANT,
+                morphology_default_border_value().unwrap(),
+            )
+            .unwrap();
+            if count_non_zero(&confident).unwrap() < self.config.min_pixels {
+                continue;
+            }
+
+            // Wrapped hue ranges are split in two, the second starting from 0,
+            // so its hues are counted from 180 to average them with the first.
+            let (mut total, mut sum) = (0.0, [0.0; 3]);
+            for (i, bound) in bounds.iter().enumerate() {
+                let mut part = Mat::default();
+                bitwise_and(
+                    &confident,
+                    &path::threshold(image, &[*bound]),
+                    &mut part,
+                    &Mat::default(),
+                )
+                .unwrap();
+                let pixels = count_non_zero(&part).unwrap() as f64;
+                if pixels == 0.0 {
+                    continue;
+                }
+                let part_mean = mean(image, &part).unwrap();
+                for (channel, sum) in sum.iter_mut().enumerate() {
+                    let offset = if channel == 0 && i > 0 { HUES } else { 0.0 };
+                    *sum += pixels * (part_mean[channel] + offset);
+                }
+                total += pixels;
+            }
+            let mut sample = sum.map(|sum| sum / total);
+            if *space == ColourSpace::Hsv {
+                sample[0] = sample[0].rem_euclid(HUES);
+            }
+            tracker.update(sample, *space, self.config.rate);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::{Body, Response, StatusCode};
+use gotham::mime::{self, Mime};
+use gotham::prelude::*;
+use gotham::state::State;
+use serde::Deserialize;
+
+/// Files served by the remote, embedded in the binary.
+/// Each is (name, content type, content).
+const ASSETS: [(&str, &str, &[u8]); 4] = [
+    (
+        "index.html",
+        "text/html; charset=utf-8",
+        include_bytes!("../assets/index.html"),
+    ),
+    (
+        "login.html",
+        "text/html; charset=utf-8",
+        include_bytes!("../assets/login.html"),
+    ),
+    (
+        "dashboard.js",
+        "text/javascript; charset=utf-8",
+        include_bytes!("../assets/dashboard.js"),
+    ),
+    (
+        "style.css",
+        "text/css; charset=utf-8",
+        include_bytes!("../assets/style.css"),
+    ),
+];
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct AssetPath {
+    name: String,
+}
+
+/// Returns a response with the named asset, or 404 if there is none.
+fn asset_response(state: &State, name: &str) -> Response<Body> {
+    match ASSETS.iter().find(|(asset, _, _)| *asset == name) {
+        Some((_, content_type, content)) => create_response(
+            state,
+            StatusCode::OK,
+            content_type.parse::<Mime>().unwrap(),
+            *content,
+        ),
+        None => create_response(
+            state,
+            StatusCode::NOT_FOUND,
+            mime::TEXT_PLAIN,
+            format!("No asset named {name}"),
+        ),
+    }
+}
+
+pub fn index(state: State) -> (State, Response<Body>) {
+    let res = asset_response(&state, "index.html");
+    (state, res)
+}
+
+pub fn login(state: State) -> (State, Response<Body>) {
+    let res = asset_response(&state, "login.html");
+    (state, res)
+}
+
+pub fn asset(state: State) -> (State, Response<Body>) {
+    let res = asset_response(&state, &AssetPath::borrow_from(&state).name);
+    (state, res)
+}
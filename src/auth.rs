@@ -0,0 +1,144 @@
+use std::future;
+use std::pin::Pin;
+
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::AUTHORIZATION;
+use gotham::hyper::{HeaderMap, Method, StatusCode, Uri};
+use gotham::middleware::Middleware;
+use gotham::mime;
+use gotham::prelude::*;
+use gotham::state::State;
+use serde::Deserialize;
+
+use crate::config::{self, ConfigError, Field};
+use crate::remote::cookie;
+
+/// Name of the cookie the login page stores the token in.
+pub const TOKEN_COOKIE: &str = "token";
+
+/// Paths that never require a token.
+const OPEN_PATHS: [&str; 5] = [
+    "/",
+    "/login",
+    "/assets/dashboard.js",
+    "/assets/login.html",
+    "/assets/style.css",
+];
+
+/// Models the [auth] table of the config.
+#[derive(Clone)]
+pub struct AuthConfig {
+    /// Shared secret clients must present.
+    /// Auth is disabled if this is None.
+    pub token: Option<String>,
+    /// Whether read-only (GET) endpoints also require the token.
+    pub protect_reads: bool,
+}
+
+/// Keys of the [auth] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct AuthSchema {
+    token: Field<String>,
+    protect_reads: Field<bool>,
+}
+
+impl AuthConfig {
+    /// Returns auth disabled if there is no [auth] table.
+    pub fn from_toml(path: &str) -> Result<Self, Vec<ConfigError>> {
+        let config = config::section(path, "auth", |schema: AuthSchema, checker| Self {
+            token: checker
+                .get("auth.token", schema.token)
+                .map(|(token, _)| token)
+                .filter(|token| !token.is_empty()),
+            protect_reads: checker
+                .get("auth.protect_reads", schema.protect_reads)
+                .map_or(false, |(protect_reads, _)| protect_reads),
+        })?;
+        Ok(config.unwrap_or_else(Self::disabled))
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            token: None,
+            protect_reads: false,
+        }
+    }
+}
+
+/// Returns the token presented in a bearer Authorization header or the token cookie.
+pub fn presented_token(headers: &HeaderMap) -> Option<&str> {
+    if let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix("Bearer "))
+    {
+        return Some(token.trim());
+    }
+
+    cookie(headers, TOKEN_COOKIE)
+}
+
+/// Compares two tokens in time independent of where they differ.
+pub fn tokens_match(expected: &str, presented: &str) -> bool {
+    expected.len() == presented.len()
+        && expected
+            .bytes()
+            .zip(presented.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Rejects requests that do not present the shared secret.
+#[derive(Clone, NewMiddleware)]
+pub struct AuthMiddleware {
+    config: AuthConfig,
+}
+
+impl AuthMiddleware {
+    pub fn new(config: AuthConfig) -> Self {
+        if config.token.is_none() {
+            log::warn!("No auth token configured, remote control is open to everyone.");
+        }
+        Self { config }
+    }
+
+    /// Returns true if the request in state must present the token.
+    fn requires_token(&self, state: &State) -> bool {
+        if self.config.token.is_none() || OPEN_PATHS.contains(&Uri::borrow_from(state).path()) {
+            return false;
+        }
+        match *Method::borrow_from(state) {
+            Method::GET | Method::HEAD => self.config.protect_reads,
+            _ => true,
+        }
+    }
+}
+
+impl Middleware for AuthMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+        Self: Sized,
+    {
+        if !self.requires_token(&state) {
+            return chain(state);
+        }
+
+        let expected = self.config.token.as_deref().unwrap();
+        let authorised = presented_token(HeaderMap::borrow_from(&state))
+            .is_some_and(|token| tokens_match(expected, token));
+        if authorised {
+            chain(state)
+        } else {
+            let res = create_response(
+                &state,
+                StatusCode::UNAUTHORIZED,
+                mime::TEXT_PLAIN,
+                "Unauthorised, log in at /login",
+            );
+            Box::pin(future::ready(Ok((state, res))))
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Finds thresholds from labelled regions of a frame.
+//!
+//! Usage: io-calibrate <source> [--select | --<label> <x,y,width,height>...]
+//!                     [--percentile <p>] [--profile <name>] [--write]
+//!
+//! Sources:
+//!     --camera <index>               Grab a frame from a camera.
+//!     --video <path> [--frame <n>]   Take the nth frame of a video, the first by default.
+//!     --image <path>                 Read a still image.
+//!
+//! Labels are left, right, box, car, finish and floor.
+//! Each may be given several times to cover more of a class.
+//! With --select, regions are also drawn over the frame for each label in turn.
+//!
+//! Each labelled class gets the range between the pth and (100 - p)th percentiles
+//! of its pixels, 1 by default, in the colour space it uses in thresholds.toml.
+//! The thresholds are printed as TOML, or written into thresholds.toml with --write.
+//! How much of each label the other classes' thresholds take in is reported too,
+//! including the floor, which should be none.
+
+use std::process::exit;
+
+use opencv::core::{Mat, Rect, Vector};
+use opencv::highgui::{destroy_all_windows, select_rois};
+use opencv::imgcodecs::{imread, IMREAD_COLOR};
+use opencv::prelude::*;
+use opencv::videoio::{VideoCapture, CAP_ANY, CAP_PROP_POS_FRAMES};
+
+use immovable_object::calibrate::{self, Label};
+use immovable_object::config;
+use immovable_object::path::DrivableConfig;
+
+const USAGE: &str =
+    "Usage: io-calibrate (--camera <index> | --video <path> [--frame <n>] | --image <path>) \
+    [--select | --<left|right|box|car|finish|floor> <x,y,width,height>...] \
+    [--percentile <p>] [--profile <name>] [--write]";
+
+/// Frames to skip from a camera while its exposure settles.
+const WARM_UP_FRAMES: usize = 10;
+
+enum Source {
+    Camera(i32),
+    Video(String),
+    Image(String),
+}
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    exit(2);
+}
+
+fn main() {
+    let mut source = None;
+    let mut frame_index = 0.0;
+    let mut regions = vec![];
+    let mut select = false;
+    let mut percentile = 1.0;
+    let mut profile = None;
+    let mut write = false;
+
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut val = || args.next().unwrap_or_else(|| usage()).clone();
+        match arg.as_str() {
+            "--camera" => source = Some(Source::Camera(val().parse().unwrap_or_else(|_| usage()))),
+            "--video" => source = Some(Source::Video(val())),
+            "--image" => source = Some(Source::Image(val())),
+            "--frame" => frame_index = val().parse().unwrap_or_else(|_| usage()),
+            "--select" => select = true,
+            "--percentile" => {
+                percentile = val().parse().unwrap_or_else(|_| usage());
+                if !(0.0..50.0).contains(&percentile) {
+                    eprintln!("--percentile must be at least 0 and below 50.");
+                    exit(2);
+                }
+            }
+            "--profile" => profile = Some(val()),
+            "--write" => write = true,
+            flag => {
+                let Some(label) = flag.strip_prefix("--").and_then(Label::from_name) else {
+                    usage();
+                };
+                let region = val();
+                let region = parse_rect(&region).unwrap_or_else(|| {
+                    eprintln!("Regions must be x,y,width,height, got {flag} {region}");
+                    exit(2);
+                });
+                regions.push((label, region));
+            }
+        }
+    }
+    let Some(source) = source else {
+        usage();
+    };
+
+    let frame = read_frame(source, frame_index).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        exit(1);
+    });
+    if select {
+        regions.extend(select_regions(&frame));
+    }
+    if regions.is_empty() {
+        eprintln!("No regions were labelled.");
+        exit(2);
+    }
+
+    let mut config = DrivableConfig::from_toml("thresholds.toml", profile.as_deref())
+        .unwrap_or_else(|errors| {
+            eprintln!("{}", config::report("thresholds.toml", &errors));
+            exit(1);
+        });
+    let overlaps =
+        calibrate::calibrate(&frame, &regions, &mut config, percentile).unwrap_or_else(|err| {
+            eprintln!("{err}");
+            exit(1);
+        });
+
+    for overlap in &overlaps {
+        eprintln!(
+            "{:.1}% of {} pixels are within the {} thresholds",
+            overlap.fraction * 100.0,
+            overlap.other.name(),
+            overlap.class.name()
+        );
+    }
+    if overlaps.is_empty() {
+        eprintln!("No labels overlap.");
+    }
+
+    let calibrated = regions
+        .iter()
+        .map(|(label, _)| *label)
+        .collect::<Vec<Label>>();
+    for label in Label::ALL.iter().filter(|label| calibrated.contains(label)) {
+        let Some(thresholds) = label.thresholds(&mut config) else {
+            continue;
+        };
+        let name = label.name();
+        let range = thresholds.ranges[0];
+        println!("{name}_space = \"{}\"", thresholds.space.name());
+        println!("{name}_lower = {:?}", range.lower);
+        println!("{name}_upper = {:?}", range.upper);
+    }
+
+    if write {
+        if let Err(err) = config.save_toml("thresholds.toml") {
+            eprintln!("Failed to write thresholds.toml: {err}");
+            exit(1);
+        }
+        eprintln!("Wrote the {} profile of thresholds.toml.", config.profile);
+    }
+}
+
+/// Parses a region given as x,y,width,height.
+fn parse_rect(val: &str) -> Option<Rect> {
+    let parts = val
+        .split(',')
+        .map(|part| part.trim().parse::<i32>().ok())
+        .collect::<Option<Vec<i32>>>()?;
+    match parts[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Some(Rect::new(x, y, width, height)),
+        _ => None,
+    }
+}
+
+fn read_frame(source: Source, frame_index: f64) -> Result<Mat, String> {
+    let mut frame = Mat::default();
+    match source {
+        Source::Image(path) => {
+            frame = imread(&path, IMREAD_COLOR)
+                .map_err(|err| format!("Failed to read {path}: {err}"))?;
+        }
+        Source::Camera(index) => {
+            let mut cap = VideoCapture::new(index, CAP_ANY)
+                .map_err(|err| format!("Failed to open camera {index}: {err}"))?;
+            for _ in 0..WARM_UP_FRAMES {
+                cap.read(&mut frame)
+                    .map_err(|err| format!("Failed to read camera {index}: {err}"))?;
+            }
+        }
+        Source::Video(path) => {
+            let mut cap = VideoCapture::from_file(&path, CAP_ANY)
+                .map_err(|err| format!("Failed to open {path}: {err}"))?;
+            cap.set(CAP_PROP_POS_FRAMES, frame_index)
+                .map_err(|err| format!("Failed to seek {path}: {err}"))?;
+            cap.read(&mut frame)
+                .map_err(|err| format!("Failed to read {path}: {err}"))?;
+        }
+    }
+    if frame.empty() {
+        return Err("No frame was read.".to_string());
+    }
+    Ok(frame)
+}
+
+/// Has regions drawn over the frame for each label in turn.
+fn select_regions(frame: &Mat) -> Vec<(Label, Rect)> {
+    let mut regions = vec![];
+    for label in Label::ALL {
+        let window = format!(
+            "Draw {} regions, enter after each, escape when done",
+            label.name()
+        );
+        let mut rects = Vector::<Rect>::new();
+        select_rois(&window, frame, &mut rects, true, false).unwrap();
+        regions.extend(rects.iter().map(|rect| (label, rect)));
+    }
+    destroy_all_windows().unwrap();
+    regions
+}
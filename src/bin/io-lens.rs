@@ -0,0 +1,209 @@
+//! Calibrates the camera's lens from views of a chessboard.
+//!
+//! Usage: io-lens (--video <path> [--every <n>] | --image <path>...)
+//!                --board <columns>x<rows> [--write]
+//!
+//! The board is given by its inner corners, e.g. 9x6 for a board of 10 by 7 squares.
+//! Every nth frame of a video is used, 15 by default, or each image given.
+//! Views should show the board at different positions, angles and distances,
+//! including near the edges of the frame where distortion is worst.
+//!
+//! The intrinsics and distortion are printed as the [camera] table of thresholds.toml,
+//! or written into it with --write, and frames are undistorted before they are considered.
+
+use std::process::exit;
+
+use opencv::calib3d::{
+    calibrate_camera, find_chessboard_corners, CALIB_CB_ADAPTIVE_THRESH, CALIB_CB_NORMALIZE_IMAGE,
+};
+use opencv::core::{
+    Mat, Point2f, Point3f, Size, TermCriteria, TermCriteria_COUNT, TermCriteria_EPS, Vector,
+};
+use opencv::imgcodecs::{imread, IMREAD_COLOR};
+use opencv::imgproc::{corner_sub_pix, cvt_color, COLOR_BGR2GRAY};
+use opencv::prelude::*;
+use opencv::videoio::{VideoCapture, CAP_ANY};
+
+use immovable_object::camera::CameraConfig;
+
+const USAGE: &str = "Usage: io-lens (--video <path> [--every <n>] | --image <path>...) \
+    --board <columns>x<rows> [--write]";
+
+/// Fewest views of the board to calibrate from.
+const MIN_VIEWS: usize = 5;
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    exit(2);
+}
+
+fn main() {
+    let mut video = None;
+    let mut every = 15;
+    let mut images = vec![];
+    let mut board = None;
+    let mut write = false;
+
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut val = || args.next().unwrap_or_else(|| usage()).clone();
+        match arg.as_str() {
+            "--video" => video = Some(val()),
+            "--every" => {
+                every = val()
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .unwrap_or_else(|| usage())
+            }
+            "--image" => images.push(val()),
+            "--board" => board = Some(parse_board(&val()).unwrap_or_else(|| usage())),
+            "--write" => write = true,
+            _ => usage(),
+        }
+    }
+    let Some(board) = board else {
+        usage();
+    };
+    let frames = match video {
+        Some(path) if images.is_empty() => read_video(&path, every),
+        None if !images.is_empty() => read_images(&images),
+        _ => usage(),
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("{err}");
+        exit(1);
+    });
+
+    let Some(resolution) = frames.first().map(|frame| frame.size().unwrap()) else {
+        eprintln!("No frames were read.");
+        exit(1);
+    };
+    let mut image_points = Vector::<Vector<Point2f>>::new();
+    for frame in &frames {
+        if frame.size().unwrap() != resolution {
+            eprintln!("Every frame must be the same size, {resolution:?}.");
+            exit(1);
+        }
+        if let Some(corners) = find_corners(frame, board) {
+            image_points.push(corners);
+        }
+    }
+    eprintln!(
+        "Found the board in {} of {} frames.",
+        image_points.len(),
+        frames.len()
+    );
+    if image_points.len() < MIN_VIEWS {
+        eprintln!("At least {MIN_VIEWS} views of the board are needed.");
+        exit(1);
+    }
+
+    // Corners of the board in units of squares, which don't matter for the intrinsics.
+    let corners = || {
+        (0..board.height)
+            .flat_map(|row| {
+                (0..board.width).map(move |col| Point3f::new(col as f32, row as f32, 0.0))
+            })
+            .collect::<Vector<Point3f>>()
+    };
+    let object_points = image_points
+        .iter()
+        .map(|_| corners())
+        .collect::<Vector<Vector<Point3f>>>();
+    let (mut matrix, mut distortion) = (Mat::default(), Mat::default());
+    let (mut rvecs, mut tvecs) = (Vector::<Mat>::new(), Vector::<Mat>::new());
+    let error = calibrate_camera(
+        &object_points,
+        &image_points,
+        resolution,
+        &mut matrix,
+        &mut distortion,
+        &mut rvecs,
+        &mut tvecs,
+        0,
+        TermCriteria::new(TermCriteria_COUNT + TermCriteria_EPS, 30, f64::EPSILON).unwrap(),
+    )
+    .unwrap();
+    eprintln!("Reprojection error is {error:.3} pixels, good calibrations are under 1.");
+
+    let at = |row, col| *matrix.at_2d::<f64>(row, col).unwrap();
+    let config = CameraConfig {
+        resolution: (resolution.width, resolution.height),
+        matrix: [at(0, 0), at(1, 1), at(0, 2), at(1, 2)],
+        distortion: distortion.data_typed::<f64>().unwrap().to_vec(),
+    };
+    print!("{}", config.to_toml());
+
+    if write {
+        if let Err(err) = config.save_toml("thresholds.toml") {
+            eprintln!("Failed to write thresholds.toml: {err}");
+            exit(1);
+        }
+        eprintln!("Wrote the camera table of thresholds.toml.");
+    }
+}
+
+/// Parses the inner corners of a board given as <columns>x<rows>.
+fn parse_board(val: &str) -> Option<Size> {
+    let (columns, rows) = val.split_once('x')?;
+    let (columns, rows) = (columns.parse().ok()?, rows.parse().ok()?);
+    (columns > 1 && rows > 1).then_some(Size::new(columns, rows))
+}
+
+fn read_video(path: &str, every: usize) -> Result<Vec<Mat>, String> {
+    let mut cap = VideoCapture::from_file(path, CAP_ANY)
+        .map_err(|err| format!("Failed to open {path}: {err}"))?;
+    let mut frames = vec![];
+    let mut frame = Mat::default();
+    let mut index = 0;
+    while cap
+        .read(&mut frame)
+        .map_err(|err| format!("Failed to read {path}: {err}"))?
+    {
+        if index % every == 0 {
+            frames.push(frame.try_clone().unwrap());
+        }
+        index += 1;
+    }
+    Ok(frames)
+}
+
+fn read_images(paths: &[String]) -> Result<Vec<Mat>, String> {
+    paths
+        .iter()
+        .map(|path| match imread(path, IMREAD_COLOR) {
+            Ok(image) if !image.empty() => Ok(image),
+            Ok(_) => Err(format!("Failed to read {path}")),
+            Err(err) => Err(format!("Failed to read {path}: {err}")),
+        })
+        .collect()
+}
+
+/// Returns the inner corners of the board in a BGR frame, refined to below a pixel,
+/// or None if it isn't all in view.
+fn find_corners(frame: &Mat, board: Size) -> Option<Vector<Point2f>> {
+    let mut gray = Mat::default();
+    cvt_color(frame, &mut gray, COLOR_BGR2GRAY, 0).unwrap();
+    let mut corners = Vector::<Point2f>::new();
+    let found = find_chessboard_corners(
+        &gray,
+        board,
+        &mut corners,
+        CALIB_CB_ADAPTIVE_THRESH + CALIB_CB_NORMALIZE_IMAGE,
+    )
+    .unwrap();
+    if !found {
+        return None;
+    }
+    corner_sub_pix(
+        &gray,
+        &mut corners,
+        Size::new(11, 11),
+        Size::new(-1, -1),
+        TermCriteria::new(TermCriteria_COUNT + TermCriteria_EPS, 30, 0.001).unwrap(),
+    )
+    .unwrap();
+    Some(corners)
+}
@@ -0,0 +1,51 @@
+//! Motor daemon, owns the car's PWM pins so the vision process can crash
+//! and restart without leaving the motors running.
+//!
+//! Usage: io-motord [--address <unix:path | host:port>]
+//!
+//! Listens on daemon.address from thresholds.toml, a Unix socket by default.
+//! Clients connecting over TCP must present the token in [auth].
+//! See src/ipc.rs for the protocol.
+
+use std::process::exit;
+use std::time::Duration;
+
+use immovable_object::auth::AuthConfig;
+use immovable_object::config;
+use immovable_object::ipc::{self, DaemonConfig, Endpoint};
+use immovable_object::logging::{LogConfig, Logger};
+use immovable_object::motor::Car;
+
+const DEFAULT_ADDRESS: &str = "unix:/tmp/immovable-object-motor.sock";
+
+fn main() {
+    let mut log_config =
+        config::or_exit("thresholds.toml", LogConfig::from_toml("thresholds.toml"));
+    // Keep out of the vision process' log file, they rotate independently.
+    log_config.file = log_config.file.map(|file| format!("{file}.motor"));
+    Logger::init(log_config);
+
+    let mut daemon = config::or_exit(
+        "thresholds.toml",
+        DaemonConfig::from_toml("thresholds.toml"),
+    )
+    .unwrap_or(DaemonConfig {
+        address: Endpoint::parse(DEFAULT_ADDRESS),
+        watchdog: Duration::from_millis(500),
+    });
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    match &args[..] {
+        [] => {}
+        [flag, address] if flag == "--address" => daemon.address = Endpoint::parse(address),
+        _ => {
+            eprintln!("Usage: io-motord [--address <unix:path | host:port>]");
+            exit(2);
+        }
+    }
+
+    let token = config::or_exit("thresholds.toml", AuthConfig::from_toml("thresholds.toml")).token;
+    if let Err(err) = ipc::serve(Car::default(), daemon, token) {
+        log::error!("{err}");
+        exit(1);
+    }
+}
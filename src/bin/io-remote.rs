@@ -0,0 +1,83 @@
+//! Controls the car over the UDP protocol.
+//!
+//! Usage: io-remote <host:port> [--token <token>] <command>
+//!
+//! Commands:
+//!     stop                    Disable the car.
+//!     arm                     Enable the car.
+//!     teleop <angle> <speed>  Drive at angle (-90 to 90) and % speed.
+//!     watch                   Print telemetry until interrupted.
+
+use std::process::exit;
+use std::time::Instant;
+
+use immovable_object::udp::{Client, Message};
+
+const USAGE: &str =
+    "Usage: io-remote <host:port> [--token <token>] (stop | arm | teleop <angle> <speed> | watch)";
+
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<String>>();
+    if args.is_empty() {
+        eprintln!("{USAGE}");
+        exit(2);
+    }
+    let addr = args.remove(0);
+    let mut token = String::new();
+    if args.first().is_some_and(|arg| arg == "--token") && args.len() >= 2 {
+        args.remove(0);
+        token = args.remove(0);
+    }
+
+    let mut client = Client::connect(addr.as_str(), &token).unwrap_or_else(|err| {
+        eprintln!("Failed to connect to {addr}: {err}");
+        exit(1);
+    });
+
+    let result = match args.iter().map(String::as_str).collect::<Vec<&str>>()[..] {
+        ["stop"] => client.stop(),
+        ["arm"] => client.arm(),
+        ["teleop", angle, speed] => {
+            let (Ok(angle), Ok(speed)) = (angle.parse(), speed.parse()) else {
+                eprintln!("{USAGE}");
+                exit(2);
+            };
+            client.teleop(angle, speed)
+        }
+        ["watch"] => watch(&mut client),
+        _ => {
+            eprintln!("{USAGE}");
+            exit(2);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("{err}");
+        exit(1);
+    }
+}
+
+/// Prints telemetry as it arrives, resubscribing before the subscription lapses.
+fn watch(client: &mut Client) -> std::io::Result<()> {
+    client.subscribe()?;
+    let mut subscribed = Instant::now();
+    loop {
+        if subscribed.elapsed().as_secs() >= 2 {
+            client.subscribe()?;
+            subscribed = Instant::now();
+        }
+        if let Ok(packet) = client.recv() {
+            if let Message::Telemetry(telemetry) = packet.msg {
+                println!(
+                    "#{} enabled: {} angle: {:.1} speed: {:.0} duty: {:.4}/{:.4}",
+                    packet.seq,
+                    telemetry.enabled,
+                    telemetry.angle,
+                    telemetry.speed,
+                    telemetry.left_duty_cycle,
+                    telemetry.right_duty_cycle
+                );
+            }
+        }
+    }
+}
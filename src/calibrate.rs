@@ -0,0 +1,226 @@
+//! Finds thresholds from labelled regions of a frame, see src/bin/io-calibrate.rs.
+//!
+//! Each class gets the range covering the middle of its pixels on every channel,
+//! so a few stray pixels in a region don't widen it.
+//! Hues are treated as circular, so reds either side of 0 get a range that wraps around
+//! rather than one spanning every hue.
+
+use std::collections::HashMap;
+
+use opencv::core::{Mat, Rect, Vec3b};
+use opencv::prelude::*;
+
+use crate::path::{ColourRange, ColourSpace, DrivableConfig, Thresholds};
+
+/// Number of hues, as OpenCV stores them.
+const HUES: usize = 180;
+
+/// Kinds of region that can be labelled.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Label {
+    Left,
+    Right,
+    Box,
+    Car,
+    Finish,
+    /// Not detected, only checked against the other classes' thresholds.
+    Floor,
+}
+
+impl Label {
+    pub const ALL: [Label; 6] = [
+        Self::Left,
+        Self::Right,
+        Self::Box,
+        Self::Car,
+        Self::Finish,
+        Self::Floor,
+    ];
+
+    /// Returns the name of the label, the prefix of its keys in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Left => "left",
+            Self::Right => "right",
+            Self::Box => "box",
+            Self::Car => "car",
+            Self::Finish => "finish",
+            Self::Floor => "floor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|label| label.name() == name)
+    }
+
+    /// Returns the thresholds of the class in config, None for the floor.
+    pub fn thresholds<'a>(&self, config: &'a mut DrivableConfig) -> Option<&'a mut Thresholds> {
+        match self {
+            Self::Left => Some(&mut config.left),
+            Self::Right => Some(&mut config.right),
+            Self::Box => Some(&mut config.boxes),
+            Self::Car => Some(&mut config.cars),
+            Self::Finish => Some(&mut config.finish),
+            Self::Floor => None,
+        }
+    }
+}
+
+/// Share of one label's pixels that fall within another class's new thresholds.
+pub struct Overlap {
+    pub class: Label,
+    pub other: Label,
+    pub fraction: f64,
+}
+
+/// Returns the range covering the pixels between percentile and 100 - percentile on each channel.
+/// pixels must not be empty.
+pub fn fit_range(pixels: &[[u8; 3]], space: ColourSpace, percentile: f64) -> ColourRange {
+    let mut range = ColourRange {
+        lower: [0; 3],
+        upper: [0; 3],
+    };
+    for channel in 0..3 {
+        // Rotate hues to start after the widest gap between them, so none are split.
+        let hue = space == ColourSpace::Hsv && channel == 0;
+        let offset = if hue { hue_offset(pixels) } else { 0 };
+        let mut vals = pixels
+            .iter()
+            .map(|pixel| {
+                let val = pixel[channel] as usize;
+                if hue {
+                    (val + HUES - offset) % HUES
+                } else {
+                    val
+                }
+            })
+            .collect::<Vec<usize>>();
+        vals.sort_unstable();
+        let at = |percentile: f64| {
+            let val = vals[(percentile / 100.0 * (vals.len() - 1) as f64).round() as usize];
+            if hue {
+                ((val + offset) % HUES) as u8
+            } else {
+                val as u8
+            }
+        };
+        range.lower[channel] = at(percentile);
+        range.upper[channel] = at(100.0 - percentile);
+    }
+    range
+}
+
+/// Returns the hue just after the widest run of hues none of the pixels have,
+/// or 0 if every hue is used.
+fn hue_offset(pixels: &[[u8; 3]]) -> usize {
+    let mut used = [false; HUES];
+    for pixel in pixels {
+        used[pixel[0] as usize % HUES] = true;
+    }
+    let Some(first) = used.iter().position(|used| *used) else {
+        return 0;
+    };
+    // Walk once around from a used hue, measuring each gap.
+    let (mut widest, mut offset, mut gap) = (0, 0, 0);
+    for step in 1..=HUES {
+        let hue = (first + step) % HUES;
+        if used[hue] {
+            if gap > widest {
+                widest = gap;
+                offset = hue;
+            }
+            gap = 0;
+        } else {
+            gap += 1;
+        }
+    }
+    offset
+}
+
+/// Returns the fraction of pixels within thresholds.
+pub fn coverage(thresholds: &Thresholds, pixels: &[[u8; 3]]) -> f64 {
+    if pixels.is_empty() {
+        return 0.0;
+    }
+    let bounds = thresholds.bounds();
+    let within = pixels
+        .iter()
+        .filter(|pixel| {
+            bounds
+                .iter()
+                .any(|(lower, upper)| (0..3).all(|i| (lower[i]..=upper[i]).contains(&pixel[i])))
+        })
+        .count();
+    within as f64 / pixels.len() as f64
+}
+
+/// Returns the pixels of image in regions.
+fn region_pixels(image: &Mat, regions: &[Rect]) -> Result<Vec<[u8; 3]>, String> {
+    let mut pixels = vec![];
+    for region in regions {
+        let roi = Mat::roi(image, *region)
+            .map_err(|err| format!("Region {region:?} is not inside the frame: {err}"))?;
+        for y in 0..roi.rows() {
+            for x in 0..roi.cols() {
+                pixels.push(roi.at_2d::<Vec3b>(y, x).unwrap().0);
+            }
+        }
+    }
+    Ok(pixels)
+}
+
+/// Sets the thresholds of each labelled class in config from its regions of a BGR frame,
+/// in the colour space the class already uses.
+/// Returns how much of each label falls within the other classes' new thresholds.
+pub fn calibrate(
+    bgr: &Mat,
+    regions: &[(Label, Rect)],
+    config: &mut DrivableConfig,
+    percentile: f64,
+) -> Result<Vec<Overlap>, String> {
+    let mut converted = HashMap::new();
+    let mut pixels = |label: Label, space: ColourSpace| {
+        let image = converted.entry(space).or_insert_with(|| space.convert(bgr));
+        let rects = regions
+            .iter()
+            .filter(|(region_label, _)| *region_label == label)
+            .map(|(_, rect)| *rect)
+            .collect::<Vec<Rect>>();
+        region_pixels(image, &rects)
+    };
+
+    let mut labels = regions
+        .iter()
+        .map(|(label, _)| *label)
+        .collect::<Vec<Label>>();
+    labels.sort_by_key(|label| Label::ALL.iter().position(|other| other == label));
+    labels.dedup();
+    let mut calibrated = vec![];
+    for label in &labels {
+        let Some(thresholds) = label.thresholds(config) else {
+            continue;
+        };
+        let space = thresholds.space;
+        let label_pixels = pixels(*label, space)?;
+        if label_pixels.is_empty() {
+            return Err(format!("The {} regions are empty", label.name()));
+        }
+        thresholds.ranges = vec![fit_range(&label_pixels, space, percentile)];
+        calibrated.push((*label, thresholds.clone()));
+    }
+
+    let mut overlaps = vec![];
+    for (class, thresholds) in &calibrated {
+        for other in labels.iter().filter(|other| *other != class) {
+            let fraction = coverage(thresholds, &pixels(*other, thresholds.space)?);
+            if fraction > 0.0 {
+                overlaps.push(Overlap {
+                    class: *class,
+                    other: *other,
+                    fraction,
+                });
+            }
+        }
+    }
+    Ok(overlaps)
+}
@@ -0,0 +1,234 @@
+//! Undoes lens distortion, see the [camera] table of thresholds.toml and src/bin/io-lens.rs.
+//!
+//! The maps from undistorted to distorted pixels are computed once,
+//! so each frame only needs remapping.
+
+use std::fs;
+use std::io;
+
+use opencv::calib3d::{get_optimal_new_camera_matrix, init_undistort_rectify_map};
+use opencv::core::{Mat, Rect, Scalar, Size, BORDER_CONSTANT, CV_16SC2};
+use opencv::imgproc::{remap, INTER_LINEAR};
+use opencv::prelude::*;
+use serde::Deserialize;
+use toml_edit::Document;
+
+use crate::config::{self, ConfigError, Field};
+use crate::path::DrivableConfig;
+
+/// Numbers of distortion coefficients OpenCV accepts.
+const DISTORTION_LENGTHS: [usize; 5] = [4, 5, 8, 12, 14];
+
+/// Models the [camera] table of the config.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CameraConfig {
+    /// Size of the frames the camera was calibrated with, (width, height).
+    pub resolution: (i32, i32),
+    /// Focal lengths and principal point in pixels, [fx, fy, cx, cy].
+    pub matrix: [f64; 4],
+    /// Distortion coefficients, [k1, k2, p1, p2, k3] or one of OpenCV's other lengths.
+    pub distortion: Vec<f64>,
+}
+
+/// Keys of the [camera] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct CameraSchema {
+    resolution: Field<[f64; 2]>,
+    matrix: Field<[f64; 4]>,
+    distortion: Field<Vec<f64>>,
+}
+
+impl CameraConfig {
+    /// Returns None if there is no [camera] table, i.e. frames are used as the lens sees them.
+    pub fn from_toml(path: &str) -> Result<Option<Self>, Vec<ConfigError>> {
+        let config = config::section(path, "camera", |schema: CameraSchema, checker| {
+            let missing = [
+                ("resolution", matches!(schema.resolution, Field::Missing)),
+                ("matrix", matches!(schema.matrix, Field::Missing)),
+                ("distortion", matches!(schema.distortion, Field::Missing)),
+            ];
+            for (key, _) in missing.into_iter().filter(|(_, missing)| *missing) {
+                checker.error(&format!("camera.{key}"), None, "must be set");
+            }
+
+            let resolution = match checker.get("camera.resolution", schema.resolution) {
+                Some(([width, height], _)) if width >= 1.0 && height >= 1.0 => {
+                    Some((width as i32, height as i32))
+                }
+                Some((_, line)) => {
+                    checker.error("camera.resolution", Some(line), "must be [width, height]");
+                    None
+                }
+                None => None,
+            };
+            let matrix = match checker.get("camera.matrix", schema.matrix) {
+                Some(([fx, fy, cx, cy], _)) if fx > 0.0 && fy > 0.0 => Some([fx, fy, cx, cy]),
+                Some((_, line)) => {
+                    checker.error(
+                        "camera.matrix",
+                        Some(line),
+                        "must be [fx, fy, cx, cy] with positive focal lengths",
+                    );
+                    None
+                }
+                None => None,
+            };
+            let distortion = match checker.get("camera.distortion", schema.distortion) {
+                Some((distortion, _)) if DISTORTION_LENGTHS.contains(&distortion.len()) => {
+                    Some(distortion)
+                }
+                Some((_, line)) => {
+                    checker.error(
+                        "camera.distortion",
+                        Some(line),
+                        "must have 4, 5, 8, 12 or 14 coefficients",
+                    );
+                    None
+                }
+                None => None,
+            };
+            Some(Self {
+                resolution: resolution?,
+                matrix: matrix?,
+                distortion: distortion?,
+            })
+        })?;
+        Ok(config.flatten())
+    }
+
+    /// Writes this config as the [camera] table of the config at path, keeping the rest of it.
+    pub fn save_toml(&self, path: &str) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        DrivableConfig::backup(path)?;
+        let mut doc = content
+            .parse::<Document>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if !doc.contains_key("camera") {
+            doc["camera"] = toml_edit::table();
+        }
+        let table = doc["camera"].as_table_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("camera is not a table in {path}"),
+            )
+        })?;
+        for (key, val) in self.values() {
+            DrivableConfig::set_toml_value(table, key, val);
+        }
+        DrivableConfig::write_atomic(path, doc.to_string())
+    }
+
+    /// Returns this config as the [camera] table of a TOML document.
+    pub fn to_toml(&self) -> String {
+        let mut table = toml_edit::Table::new();
+        for (key, val) in self.values() {
+            DrivableConfig::set_toml_value(&mut table, key, val);
+        }
+        let mut doc = Document::new();
+        doc["camera"] = toml_edit::Item::Table(table);
+        doc.to_string()
+    }
+
+    /// Returns the keys and values of this config.
+    fn values(&self) -> Vec<(&'static str, toml_edit::Value)> {
+        // Calibration isn't more precise than this.
+        let floats = |vals: &[f64]| {
+            vals.iter()
+                .map(|val| (val * 1e6).round() / 1e6)
+                .collect::<toml_edit::Array>()
+        };
+        let (width, height) = self.resolution;
+        vec![
+            (
+                "resolution",
+                [width as i64, height as i64]
+                    .into_iter()
+                    .collect::<toml_edit::Array>()
+                    .into(),
+            ),
+            ("matrix", floats(&self.matrix).into()),
+            ("distortion", floats(&self.distortion).into()),
+        ]
+    }
+
+    /// Returns the 3x3 camera matrix.
+    pub fn camera_matrix(&self) -> Mat {
+        let [fx, fy, cx, cy] = self.matrix;
+        Mat::from_slice_2d(&[[fx, 0.0, cx], [0.0, fy, cy], [0.0, 0.0, 1.0]]).unwrap()
+    }
+}
+
+/// Undistorts frames with the maps for a calibrated camera.
+pub struct Undistorter {
+    /// Size of frames the maps are for.
+    size: Size,
+    map1: Mat,
+    map2: Mat,
+    /// Whether a frame of the wrong size has been warned about.
+    warned: bool,
+}
+
+impl Undistorter {
+    pub fn new(config: &CameraConfig) -> Self {
+        let size = Size::new(config.resolution.0, config.resolution.1);
+        let matrix = config.camera_matrix();
+        let distortion = Mat::from_slice_2d(&[config.distortion.as_slice()]).unwrap();
+        // Scaled so every pixel of the undistorted frame is one the camera saw.
+        let new_matrix = get_optimal_new_camera_matrix(
+            &matrix,
+            &distortion,
+            size,
+            0.0,
+            size,
+            &mut Rect::default(),
+            false,
+        )
+        .unwrap();
+        let (mut map1, mut map2) = (Mat::default(), Mat::default());
+        init_undistort_rectify_map(
+            &matrix,
+            &distortion,
+            &Mat::default(),
+            &new_matrix,
+            size,
+            CV_16SC2,
+            &mut map1,
+            &mut map2,
+        )
+        .unwrap();
+        Undistorter {
+            size,
+            map1,
+            map2,
+            warned: false,
+        }
+    }
+
+    /// Returns frame undistorted, or as it is if it isn't the size the camera was calibrated at.
+    pub fn undistort(&mut self, frame: &Mat) -> Mat {
+        if frame.size().unwrap() != self.size {
+            if !self.warned {
+                log::warn!(
+                    "Frames are {:?} but the camera was calibrated at {:?}, so are not undistorted",
+                    frame.size().unwrap(),
+                    self.size
+                );
+                self.warned = true;
+            }
+            return frame.try_clone().unwrap();
+        }
+        let mut undistorted = Mat::default();
+        remap(
+            frame,
+            &mut undistorted,
+            &self.map1,
+            &self.map2,
+            INTER_LINEAR,
+            BORDER_CONSTANT,
+            Scalar::all(0.0),
+        )
+        .unwrap();
+        undistorted
+    }
+}
@@ -0,0 +1,235 @@
+//! Cleans speckle out of masks before rays are cast, see the [cleanup] table of thresholds.toml.
+//!
+//! Each class's mask is opened to remove specks and closed to fill gaps,
+//! then connected components too small or too elongated to be the class are dropped.
+
+use std::collections::HashMap;
+
+use opencv::core::{Mat, Point, Scalar, Size, BORDER_CONSTANT, CV_32S, CV_8UC1};
+use opencv::imgproc::{
+    connected_components_with_stats, get_structuring_element, morphology_default_border_value,
+    morphology_ex, CC_STAT_AREA, CC_STAT_HEIGHT, CC_STAT_WIDTH, MORPH_CLOSE, MORPH_OPEN,
+    MORPH_RECT,
+};
+use opencv::prelude::*;
+use serde::Deserialize;
+use toml::Value;
+
+use crate::config::{self, Checker, ConfigError, Field};
+
+/// Classes that can be cleaned, by the prefix of their keys in the config.
+pub const CLASSES: [&str; 5] = ["left", "right", "box", "car", "finish"];
+
+/// Cleanup of one class's mask.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cleanup {
+    /// Side of the square kernel to open with, removing specks, 0 to skip.
+    pub open: i32,
+    /// Side of the square kernel to close with, filling gaps, 0 to skip.
+    pub close: i32,
+    /// Smallest area of a connected component to keep, in pixels.
+    pub min_area: i32,
+    /// Largest ratio of the longer to the shorter side of a component's bounding box to keep,
+    /// 0 to keep any.
+    pub max_aspect: f64,
+}
+
+/// Keys of a [cleanup] table, or one of its class tables.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct CleanupKeys {
+    open: Field<i64>,
+    close: Field<i64>,
+    min_area: Field<i64>,
+    max_aspect: Field<f64>,
+}
+
+/// Keys of the [cleanup] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct CleanupSchema {
+    open: Field<i64>,
+    close: Field<i64>,
+    min_area: Field<i64>,
+    max_aspect: Field<f64>,
+    left: Option<CleanupKeys>,
+    right: Option<CleanupKeys>,
+    #[serde(rename = "box")]
+    boxes: Option<CleanupKeys>,
+    car: Option<CleanupKeys>,
+    finish: Option<CleanupKeys>,
+}
+
+impl Default for Cleanup {
+    /// Cleanup of a class when [cleanup] sets nothing for it.
+    fn default() -> Self {
+        Self {
+            open: 3,
+            close: 0,
+            min_area: 20,
+            max_aspect: 0.0,
+        }
+    }
+}
+
+impl Cleanup {
+    /// Reads the keys of a [cleanup] table, or one of its class tables, over base.
+    fn from_keys(checker: &mut Checker, keys: CleanupKeys, name: &str, base: Cleanup) -> Self {
+        let key = |key: &str| format!("{name}.{key}");
+        let max_aspect = match checker.get(&key("max_aspect"), keys.max_aspect) {
+            Some((aspect, _)) if aspect == 0.0 || aspect >= 1.0 => aspect,
+            Some((aspect, line)) => {
+                checker.error(
+                    &key("max_aspect"),
+                    Some(line),
+                    format!("must be 0 or at least 1, got {aspect}"),
+                );
+                base.max_aspect
+            }
+            None => base.max_aspect,
+        };
+        Self {
+            open: checker.int(&key("open"), keys.open, base.open as i64, 0..=255) as i32,
+            close: checker.int(&key("close"), keys.close, base.close as i64, 0..=255) as i32,
+            min_area: checker.int(
+                &key("min_area"),
+                keys.min_area,
+                base.min_area as i64,
+                0..=i32::MAX as i64,
+            ) as i32,
+            max_aspect,
+        }
+    }
+
+    /// Returns mask with specks, gaps and components that don't fit removed.
+    pub fn clean(&self, mask: &Mat) -> Mat {
+        let mut cleaned = mask.try_clone().unwrap();
+        for (op, size) in [(MORPH_OPEN, self.open), (MORPH_CLOSE, self.close)] {
+            if size < 2 {
+                continue;
+            }
+            let kernel =
+                get_structuring_element(MORPH_RECT, Size::new(size, size), Point::new(-1, -1))
+                    .unwrap();
+            let mut morphed = Mat::default();
+            morphology_ex(
+                &cleaned,
+                &mut morphed,
+                op,
+                &kernel,
+                Point::new(-1, -1),
+                1,
+                BORDER_CONSTANT,
+                morphology_default_border_value().unwrap(),
+            )
+            .unwrap();
+            cleaned = morphed;
+        }
+        if self.min_area < 2 && self.max_aspect == 0.0 {
+            return cleaned;
+        }
+
+        let (mut labels, mut stats, mut centroids) =
+            (Mat::default(), Mat::default(), Mat::default());
+        let count = connected_components_with_stats(
+            &cleaned,
+            &mut labels,
+            &mut stats,
+            &mut centroids,
+            8,
+            CV_32S,
+        )
+        .unwrap();
+        // Label 0 is the background.
+        let keep = (0..count)
+            .map(|label| {
+                if label == 0 {
+                    return false;
+                }
+                let stat = |col| *stats.at_2d::<i32>(label, col).unwrap();
+                let (width, height) = (stat(CC_STAT_WIDTH), stat(CC_STAT_HEIGHT));
+                let aspect = width.max(height) as f64 / width.min(height) as f64;
+                stat(CC_STAT_AREA) >= self.min_area
+                    && (self.max_aspect == 0.0 || aspect <= self.max_aspect)
+            })
+            .collect::<Vec<bool>>();
+        let mut kept =
+            Mat::new_rows_cols_with_default(mask.rows(), mask.cols(), CV_8UC1, Scalar::all(0.0))
+                .unwrap();
+        for (pixel, label) in kept
+            .data_typed_mut::<u8>()
+            .unwrap()
+            .iter_mut()
+            .zip(labels.data_typed::<i32>().unwrap())
+        {
+            if keep[*label as usize] {
+                *pixel = 255;
+            }
+        }
+        kept
+    }
+}
+
+/// Models the [cleanup] table of the config.
+/// Its keys apply to every class, and [cleanup.<class>] tables override them for one class.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CleanupConfig {
+    /// Cleanup of each class, by class.
+    pub classes: HashMap<&'static str, Cleanup>,
+}
+
+impl CleanupConfig {
+    /// Returns None if there is no [cleanup] table, i.e. masks are used as thresholded.
+    pub fn from_toml(path: &str) -> Result<Option<Self>, Vec<ConfigError>> {
+        // Tables that are not a class would otherwise be silently ignored.
+        let unknown = config::section(path, "cleanup", |keys: HashMap<String, Value>, _| {
+            keys.into_iter()
+                .filter(|(key, val)| val.is_table() && !CLASSES.contains(&key.as_str()))
+                .map(|(key, _)| key)
+                .collect::<Vec<String>>()
+        })?;
+        config::section(path, "cleanup", |schema: CleanupSchema, checker| {
+            for key in unknown.unwrap_or_default() {
+                checker.error(
+                    &format!("cleanup.{key}"),
+                    None,
+                    format!("is not a class, expected one of {}", CLASSES.join(", ")),
+                );
+            }
+            let keys = CleanupKeys {
+                open: schema.open,
+                close: schema.close,
+                min_area: schema.min_area,
+                max_aspect: schema.max_aspect,
+            };
+            let base = Cleanup::from_keys(checker, keys, "cleanup", Cleanup::default());
+            let tables = [
+                schema.left,
+                schema.right,
+                schema.boxes,
+                schema.car,
+                schema.finish,
+            ];
+            let classes = CLASSES
+                .into_iter()
+                .zip(tables)
+                .map(|(class, keys)| match keys {
+                    Some(keys) => (
+                        class,
+                        Cleanup::from_keys(checker, keys, &format!("cleanup.{class}"), base),
+                    ),
+                    None => (class, base),
+                })
+                .collect();
+            Self { classes }
+        })
+    }
+
+    /// Returns mask cleaned as configured for class.
+    pub fn clean(&self, class: &str, mask: &Mat) -> Mat {
+        match self.classes.get(class) {
+            Some(cleanup) => cleanup.clean(mask),
+            None => mask.try_clone().unwrap(),
+        }
+    }
+}
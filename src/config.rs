@@ -0,0 +1,803 @@
+//! Schema of the thresholds in thresholds.toml.
+//!
+//! The whole file is checked before any of it is used,
+//! so every problem is reported at once with its key and line.
+//!
+//! Each class is thresholded in its own colour space, hsv, lab or ycrcb, HSV by default.
+//! Its lower and upper bounds are each a single [hue, saturation, value] or the like,
+//! or a list of them paired in order, e.g. for multi-coloured boxes.
+//!
+//! The top level keys make up the base profile.
+//! Other profiles are tables under [profiles], e.g. for a venue or its lighting,
+//! and only set the keys that differ from the profile they inherit from:
+//!
+//! ```toml
+//! profile = "lab"
+//!
+//! [profiles.lab]
+//! speed = 0.3
+//!
+//! [profiles.venue-evening]
+//! inherits = "lab"
+//! left_lower = [20, 60, 30]
+//! ```
+//!
+//! The profile key chooses the profile used at startup, the base if unset.
+//!
+//! The other parts of the program each read their own table, e.g. [log], with [`section`],
+//! so their problems are reported the same way.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Range, RangeInclusive};
+
+use opencv::core::Rect;
+use serde::de::{DeserializeOwned, DeserializeSeed, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use toml::{Spanned, Table, Value};
+
+use crate::path::{ColourRange, ColourSpace, DrivableConfig, Thresholds};
+
+/// Name of the profile made of the top level keys.
+/// Profiles inherit from it unless they say otherwise.
+pub const BASE_PROFILE: &str = "base";
+
+/// Region of the camera frame considered when no roi is configured.
+pub const DEFAULT_ROI: Rect = Rect {
+    x: 0,
+    y: 230,
+    width: 640,
+    height: 250,
+};
+
+/// Keys of a profile other than the thresholds.
+const OTHER_KEYS: [&str; 6] = ["p_gain", "i_gain", "i_max", "speed", "roi", "inherits"];
+
+/// Keys of the thresholds of each class.
+const THRESHOLD_KEYS: [&str; 15] = [
+    "left_space",
+    "left_lower",
+    "left_upper",
+    "right_space",
+    "right_lower",
+    "right_upper",
+    "box_space",
+    "box_lower",
+    "box_upper",
+    "car_space",
+    "car_lower",
+    "car_upper",
+    "finish_space",
+    "finish_lower",
+    "finish_upper",
+];
+
+/// Problem with a key in the config.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ConfigError {
+    /// Key the problem is with, empty if it is with the file as a whole.
+    pub key: String,
+    /// Line the key is on, counting from 1.
+    pub line: Option<usize>,
+    pub msg: String,
+}
+
+impl ConfigError {
+    pub fn new(key: &str, line: Option<usize>, msg: impl Into<String>) -> Self {
+        Self {
+            key: key.to_owned(),
+            line,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        write!(f, "{}", self.msg)
+    }
+}
+
+/// Formats errors in the config at path one per line, as path:line: key: problem.
+pub fn report(path: &str, errors: &[ConfigError]) -> String {
+    errors
+        .iter()
+        .map(|err| {
+            let location = match err.line {
+                Some(line) => format!("{path}:{line}"),
+                None => path.to_owned(),
+            };
+            let key = if err.key.is_empty() {
+                String::new()
+            } else {
+                format!(" {}:", err.key)
+            };
+            format!("{location}:{key} {}", err.msg)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Returns what was read from the config at path, or prints its problems and exits.
+/// For use at startup, when there is nothing to fall back to.
+pub fn or_exit<T>(path: &str, read: Result<T, Vec<ConfigError>>) -> T {
+    read.unwrap_or_else(|errors| {
+        eprintln!("{}", report(path, &errors));
+        std::process::exit(1);
+    })
+}
+
+/// Value of a key, kept even if it has the wrong type so the error can be reported
+/// along with any others.
+#[derive(Default)]
+pub(crate) enum Field<T> {
+    #[default]
+    Missing,
+    Present {
+        span: Range<usize>,
+        val: Result<T, String>,
+    },
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Field<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Spanned::<Value>::deserialize(deserializer)?;
+        let span = value.span();
+        let val = T::deserialize(value.into_inner()).map_err(|err| err.message().to_owned());
+        Ok(Self::Present { span, val })
+    }
+}
+
+/// Keys of a profile read by the pathfinder.
+/// Missing keys are inherited.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Schema {
+    inherits: Field<String>,
+    left_space: Field<String>,
+    left_lower: Field<Value>,
+    left_upper: Field<Value>,
+    right_space: Field<String>,
+    right_lower: Field<Value>,
+    right_upper: Field<Value>,
+    box_space: Field<String>,
+    box_lower: Field<Value>,
+    box_upper: Field<Value>,
+    car_space: Field<String>,
+    car_lower: Field<Value>,
+    car_upper: Field<Value>,
+    finish_space: Field<String>,
+    finish_lower: Field<Value>,
+    finish_upper: Field<Value>,
+    p_gain: Field<f64>,
+    i_gain: Field<f64>,
+    i_max: Field<f64>,
+    speed: Field<f64>,
+    roi: Field<[i64; 4]>,
+}
+
+/// Profiles in thresholds.toml and which is used at startup.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Profiles {
+    profile: Field<String>,
+    profiles: HashMap<String, Schema>,
+}
+
+/// Keys of each profile, to catch unknown ones.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ProfileKeys {
+    profiles: HashMap<String, HashMap<String, Spanned<Value>>>,
+}
+
+/// Reads the top level keys that are not tables along with their spans.
+/// toml cannot give the span of a table only made by its subtables, e.g. [profiles.lab].
+struct TopLevelKeys<'a>(&'a Table);
+
+impl<'de> DeserializeSeed<'de> for TopLevelKeys<'_> {
+    type Value = HashMap<String, Spanned<Value>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for TopLevelKeys<'_> {
+    type Value = HashMap<String, Spanned<Value>>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a table")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut keys = HashMap::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if let Some(Value::Table(_)) = self.0.get(&key) {
+                map.next_value::<IgnoredAny>()?;
+            } else {
+                keys.insert(key, map.next_value()?);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Checks fields of a config, collecting every problem found.
+pub(crate) struct Checker<'a> {
+    content: &'a str,
+    errors: Vec<ConfigError>,
+}
+
+/// Returns the line of content a span starts on, counting from 1.
+fn line_of(content: &str, span: &Range<usize>) -> usize {
+    content[..span.start.min(content.len())]
+        .matches('\n')
+        .count()
+        + 1
+}
+
+impl Checker<'_> {
+    /// Records a problem with key, found on line if it is known.
+    pub(crate) fn error(&mut self, key: &str, line: Option<usize>, msg: impl Into<String>) {
+        self.errors.push(ConfigError::new(key, line, msg));
+    }
+
+    /// Returns the value of field if it is present and the right type.
+    pub(crate) fn get<T>(&mut self, key: &str, field: Field<T>) -> Option<(T, usize)> {
+        match field {
+            Field::Missing => None,
+            Field::Present { span, val } => {
+                let line = line_of(self.content, &span);
+                match val {
+                    Ok(val) => Some((val, line)),
+                    Err(err) => {
+                        self.errors.push(ConfigError::new(key, Some(line), err));
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads bounds given as [hue, saturation, value] or the like for the space,
+    /// or a list of them.
+    fn bounds(
+        &mut self,
+        key: &str,
+        field: Field<Value>,
+        space: ColourSpace,
+    ) -> Option<(Vec<[u8; 3]>, usize)> {
+        let channels = space.channels();
+        let (val, line) = self.get(key, field)?;
+        let (items, single) = match val {
+            Value::Array(items) if items.iter().all(Value::is_array) => (items, false),
+            val => (vec![val], true),
+        };
+        let errors = self.errors.len();
+        let mut bounds = vec![];
+        for (i, item) in items.into_iter().enumerate() {
+            let key = if single {
+                key.to_owned()
+            } else {
+                format!("{key}[{i}]")
+            };
+            let Ok(vals) = <[i64; 3]>::deserialize(item) else {
+                let names = channels.map(|(name, _)| name).join(", ");
+                self.errors.push(ConfigError::new(
+                    &key,
+                    Some(line),
+                    format!("must be [{names}] or a list of them"),
+                ));
+                continue;
+            };
+            let mut bound = [0; 3];
+            for (i, ((name, max), val)) in channels.iter().zip(vals).enumerate() {
+                if (0..=*max as i64).contains(&val) {
+                    bound[i] = val as u8;
+                } else {
+                    self.errors.push(ConfigError::new(
+                        &format!("{key}[{i}]"),
+                        Some(line),
+                        format!("{name} must be 0-{max}, got {val}"),
+                    ));
+                }
+            }
+            bounds.push(bound);
+        }
+        (self.errors.len() == errors).then_some((bounds, line))
+    }
+
+    /// Reads the colour space and bounds of a class, inheriting any that are missing.
+    fn thresholds(
+        &mut self,
+        prefix: &str,
+        class: &str,
+        (space, lower, upper): (Field<String>, Field<Value>, Field<Value>),
+        parent: &Thresholds,
+    ) -> Thresholds {
+        let space_key = format!("{prefix}{class}_space");
+        let space = match self.get(&space_key, space) {
+            Some((name, line)) => {
+                let Some(space) = ColourSpace::from_name(&name) else {
+                    let names = ColourSpace::ALL.map(|space| space.name()).join(", ");
+                    self.errors.push(ConfigError::new(
+                        &space_key,
+                        Some(line),
+                        format!("must be one of {names}, got {name}"),
+                    ));
+                    return parent.clone();
+                };
+                // Bounds in another colour space mean something else, so cannot be inherited.
+                let missing = matches!(lower, Field::Missing) || matches!(upper, Field::Missing);
+                if space != parent.space && !parent.ranges.is_empty() && missing {
+                    self.errors.push(ConfigError::new(
+                        &space_key,
+                        Some(line),
+                        format!(
+                            "{class}_lower and {class}_upper must be set too when changing from {}",
+                            parent.space.name()
+                        ),
+                    ));
+                    return parent.clone();
+                }
+                space
+            }
+            None => parent.space,
+        };
+        let ranges = self.ranges(prefix, class, space, lower, upper, &parent.ranges);
+        Thresholds { space, ranges }
+    }
+
+    /// Pairs up the lower and upper bounds of a class into ranges.
+    /// Either list of bounds is inherited if it is missing.
+    fn ranges(
+        &mut self,
+        prefix: &str,
+        class: &str,
+        space: ColourSpace,
+        lower: Field<Value>,
+        upper: Field<Value>,
+        parent: &[ColourRange],
+    ) -> Vec<ColourRange> {
+        let (lower_key, upper_key) = (
+            format!("{prefix}{class}_lower"),
+            format!("{prefix}{class}_upper"),
+        );
+        let errors = self.errors.len();
+        let (lower, upper) = (
+            self.bounds(&lower_key, lower, space),
+            self.bounds(&upper_key, upper, space),
+        );
+        if self.errors.len() > errors || (lower.is_none() && upper.is_none()) {
+            return parent.to_vec();
+        }
+        let (key, line) = match (&lower, &upper) {
+            (Some((_, line)), _) => (lower_key, *line),
+            (None, Some((_, line))) => (upper_key, *line),
+            (None, None) => unreachable!(),
+        };
+        let lower = lower.map_or_else(
+            || parent.iter().map(|range| range.lower).collect(),
+            |(bounds, _)| bounds,
+        );
+        let upper = upper.map_or_else(
+            || parent.iter().map(|range| range.upper).collect(),
+            |(bounds, _)| bounds,
+        );
+        if lower.len() != upper.len() {
+            self.errors.push(ConfigError::new(
+                &key,
+                Some(line),
+                format!(
+                    "{} lower and {} upper bounds, which are paired in order",
+                    lower.len(),
+                    upper.len()
+                ),
+            ));
+            return parent.to_vec();
+        }
+        lower
+            .into_iter()
+            .zip(upper)
+            .map(|(lower, upper)| ColourRange { lower, upper })
+            .collect()
+    }
+
+    pub(crate) fn float(
+        &mut self,
+        key: &str,
+        field: Field<f64>,
+        default: f64,
+        range: RangeInclusive<f64>,
+    ) -> f64 {
+        let Some((val, line)) = self.get(key, field) else {
+            return default;
+        };
+        if range.contains(&val) {
+            return val;
+        }
+        let expected = if range.end().is_infinite() {
+            format!("at least {}", range.start())
+        } else {
+            format!("between {} and {}", range.start(), range.end())
+        };
+        self.errors.push(ConfigError::new(
+            key,
+            Some(line),
+            format!("must be {expected}, got {val}"),
+        ));
+        default
+    }
+
+    pub(crate) fn int(
+        &mut self,
+        key: &str,
+        field: Field<i64>,
+        default: i64,
+        range: RangeInclusive<i64>,
+    ) -> i64 {
+        let Some((val, line)) = self.get(key, field) else {
+            return default;
+        };
+        if range.contains(&val) {
+            return val;
+        }
+        let expected = if *range.end() == i64::MAX {
+            format!("at least {}", range.start())
+        } else {
+            format!("between {} and {}", range.start(), range.end())
+        };
+        self.errors.push(ConfigError::new(
+            key,
+            Some(line),
+            format!("must be {expected}, got {val}"),
+        ));
+        default
+    }
+
+    fn rect(&mut self, key: &str, field: Field<[i64; 4]>, default: Rect) -> Rect {
+        let Some(([x, y, width, height], line)) = self.get(key, field) else {
+            return default;
+        };
+        let in_range = |val: i64, min: i64| (min..=i32::MAX as i64).contains(&val);
+        if !(in_range(x, 0) && in_range(y, 0) && in_range(width, 1) && in_range(height, 1)) {
+            self.errors.push(ConfigError::new(
+                key,
+                Some(line),
+                format!(
+                    "must be [x, y, width, height] with a positive size and position, got {:?}",
+                    [x, y, width, height]
+                ),
+            ));
+            return default;
+        }
+        Rect::new(x as i32, y as i32, width as i32, height as i32)
+    }
+
+    /// Applies the keys of a profile over the config it inherits from.
+    /// prefix is put before keys in errors.
+    fn profile(&mut self, prefix: &str, schema: Schema, parent: DrivableConfig) -> DrivableConfig {
+        let key = |key: &str| format!("{prefix}{key}");
+        DrivableConfig {
+            left: self.thresholds(
+                prefix,
+                "left",
+                (schema.left_space, schema.left_lower, schema.left_upper),
+                &parent.left,
+            ),
+            right: self.thresholds(
+                prefix,
+                "right",
+                (schema.right_space, schema.right_lower, schema.right_upper),
+                &parent.right,
+            ),
+            boxes: self.thresholds(
+                prefix,
+                "box",
+                (schema.box_space, schema.box_lower, schema.box_upper),
+                &parent.boxes,
+            ),
+            cars: self.thresholds(
+                prefix,
+                "car",
+                (schema.car_space, schema.car_lower, schema.car_upper),
+                &parent.cars,
+            ),
+            finish: self.thresholds(
+                prefix,
+                "finish",
+                (
+                    schema.finish_space,
+                    schema.finish_lower,
+                    schema.finish_upper,
+                ),
+                &parent.finish,
+            ),
+            p_gain: self.float(&key("p_gain"), schema.p_gain, parent.p_gain, 0.0..=1.0),
+            i_gain: self.float(&key("i_gain"), schema.i_gain, parent.i_gain, 0.0..=1.0),
+            i_max: self.float(
+                &key("i_max"),
+                schema.i_max,
+                parent.i_max,
+                0.0..=f64::INFINITY,
+            ),
+            speed: self.float(&key("speed"), schema.speed, parent.speed, 0.0..=1.0),
+            roi: self.rect(&key("roi"), schema.roi, parent.roi),
+            profile: parent.profile,
+        }
+    }
+
+    /// Reports keys that are not part of a profile, which would otherwise be silently inherited.
+    fn unknown_keys(
+        &mut self,
+        prefix: &str,
+        keys: &HashMap<String, Spanned<Value>>,
+        top_level: bool,
+    ) {
+        for (key, val) in keys {
+            let known = THRESHOLD_KEYS.contains(&key.as_str())
+                || OTHER_KEYS.contains(&key.as_str())
+                || (top_level && key == "profile");
+            if !known {
+                let line = line_of(self.content, &val.span());
+                self.errors.push(ConfigError::new(
+                    &format!("{prefix}{key}"),
+                    Some(line),
+                    "unknown key",
+                ));
+            }
+        }
+    }
+}
+
+/// Reads the keys of the table called name, of type S, from the top level of a TOML document.
+struct SectionSeed<'a, S> {
+    name: &'a str,
+    schema: PhantomData<S>,
+}
+
+impl<'de, S: Deserialize<'de>> DeserializeSeed<'de> for SectionSeed<'_, S> {
+    type Value = Option<S>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, S: Deserialize<'de>> Visitor<'de> for SectionSeed<'_, S> {
+    type Value = Option<S>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a table")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut section = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.name {
+                section = Some(map.next_value()?);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(section)
+    }
+}
+
+/// Reads the [name] table of the TOML file at path, whose keys are S, and checks them with read.
+/// Returns None if there is no such table, or every problem found if any.
+pub(crate) fn section<S: DeserializeOwned, T>(
+    path: &str,
+    name: &str,
+    read: impl FnOnce(S, &mut Checker) -> T,
+) -> Result<Option<T>, Vec<ConfigError>> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| vec![ConfigError::new("", None, format!("failed to read: {err}"))])?;
+    let syntax = |err: toml::de::Error| {
+        let line = err.span().map(|span| line_of(&content, &span));
+        vec![ConfigError::new(
+            name,
+            line,
+            err.message().trim().replace('\n', ", "),
+        )]
+    };
+    let table = toml::from_str::<Table>(&content).map_err(syntax)?;
+    match table.get(name) {
+        None => return Ok(None),
+        Some(Value::Table(_)) => {}
+        Some(_) => {
+            let line = TopLevelKeys(&table)
+                .deserialize(toml::Deserializer::new(&content))
+                .ok()
+                .and_then(|keys| keys.get(name).map(|val| line_of(&content, &val.span())));
+            return Err(vec![ConfigError::new(name, line, "must be a table")]);
+        }
+    }
+    let schema = SectionSeed {
+        name,
+        schema: PhantomData,
+    }
+    .deserialize(toml::Deserializer::new(&content))
+    .map_err(syntax)?
+    .ok_or_else(|| vec![ConfigError::new(name, None, "must be a table")])?;
+
+    let mut checker = Checker {
+        content: &content,
+        errors: vec![],
+    };
+    let section = read(schema, &mut checker);
+    if checker.errors.is_empty() {
+        Ok(Some(section))
+    } else {
+        checker.errors.sort_by_key(|err| err.line);
+        Err(checker.errors)
+    }
+}
+
+/// Returns the config used for keys no profile sets.
+fn defaults() -> DrivableConfig {
+    // Classes with no ranges detect nothing.
+    DrivableConfig {
+        left: Thresholds::default(),
+        right: Thresholds::default(),
+        boxes: Thresholds::default(),
+        cars: Thresholds::default(),
+        finish: Thresholds::default(),
+        p_gain: 1.0,
+        i_gain: 0.0,
+        i_max: 200.0,
+        speed: 0.2,
+        roi: DEFAULT_ROI,
+        profile: BASE_PROFILE.to_owned(),
+    }
+}
+
+fn syntax_error(err: &toml::de::Error, line: Option<usize>) -> ConfigError {
+    ConfigError::new("", line, err.message().trim().replace('\n', ", "))
+}
+
+/// Reads and checks the thresholds in content, a TOML document,
+/// using the profile chosen by its profile key.
+/// Returns every problem found if any.
+pub fn parse(content: &str) -> Result<DrivableConfig, Vec<ConfigError>> {
+    parse_profile(content, None)
+}
+
+/// Reads and checks the named profile in content, a TOML document,
+/// or the one chosen by its profile key if None.
+/// Returns every problem found if any.
+pub fn parse_profile(
+    content: &str,
+    profile: Option<&str>,
+) -> Result<DrivableConfig, Vec<ConfigError>> {
+    fn read<T: DeserializeOwned>(content: &str) -> Result<T, Vec<ConfigError>> {
+        toml::from_str::<T>(content).map_err(|err| {
+            let line = err.span().map(|span| line_of(content, &span));
+            vec![syntax_error(&err, line)]
+        })
+    }
+    let mut base = read::<Schema>(content)?;
+    let Profiles {
+        profile: default,
+        mut profiles,
+    } = read::<Profiles>(content)?;
+    let mut checker = Checker {
+        content,
+        errors: vec![],
+    };
+
+    // Tables at the top level belong to other parts of the program.
+    let top_level = TopLevelKeys(&read(content)?)
+        .deserialize(toml::Deserializer::new(content))
+        .map_err(|err| vec![syntax_error(&err, None)])?;
+    checker.unknown_keys("", &top_level, true);
+    for (name, keys) in read::<ProfileKeys>(content)?.profiles {
+        checker.unknown_keys(&format!("profiles.{name}."), &keys, false);
+    }
+    if let Some((_, line)) = checker.get("inherits", mem::take(&mut base.inherits)) {
+        checker.errors.push(ConfigError::new(
+            "inherits",
+            Some(line),
+            "the top level keys are the base profile, which cannot inherit",
+        ));
+    }
+    if profiles.contains_key(BASE_PROFILE) {
+        checker.errors.push(ConfigError::new(
+            &format!("profiles.{BASE_PROFILE}"),
+            None,
+            "the base profile is the top level keys and cannot be a table",
+        ));
+    }
+
+    // Follow inheritance back to the base, noting where each name came from.
+    let (mut name, mut key, mut line) = match profile {
+        Some(name) => (name.to_owned(), String::new(), None),
+        None => match checker.get("profile", default) {
+            Some((name, line)) => (name, "profile".to_owned(), Some(line)),
+            None => (BASE_PROFILE.to_owned(), String::new(), None),
+        },
+    };
+    let mut chain: Vec<(String, Schema)> = vec![];
+    while name != BASE_PROFILE {
+        let Some(mut schema) = profiles.remove(&name) else {
+            let msg = if chain.iter().any(|(inherited, _)| *inherited == name) {
+                let names = chain.iter().map(|(name, _)| name.as_str());
+                format!(
+                    "inheritance cycle {} -> {name}",
+                    names.collect::<Vec<_>>().join(" -> ")
+                )
+            } else {
+                format!("unknown profile {name}")
+            };
+            checker.errors.push(ConfigError::new(&key, line, msg));
+            break;
+        };
+        key = format!("profiles.{name}.inherits");
+        let parent = checker.get(&key, mem::take(&mut schema.inherits));
+        line = parent.as_ref().map(|(_, line)| *line);
+        chain.push((name, schema));
+        name = parent.map_or(BASE_PROFILE.to_owned(), |(parent, _)| parent);
+    }
+
+    let mut config = checker.profile("", base, defaults());
+    for (name, schema) in chain.into_iter().rev() {
+        config = checker.profile(&format!("profiles.{name}."), schema, config);
+        config.profile = name;
+    }
+
+    if checker.errors.is_empty() {
+        Ok(config)
+    } else {
+        checker.errors.sort_by_key(|err| err.line);
+        Err(checker.errors)
+    }
+}
+
+/// Returns the key that sets key for the named profile of content, a TOML document,
+/// and the line it is on, following inheritance back to the base.
+/// Returns None if no profile sets it, i.e. it is the default.
+pub fn setting(content: &str, profile: &str, key: &str) -> Option<(String, usize)> {
+    let table = toml::from_str::<Table>(content).ok()?;
+    let top_level = TopLevelKeys(&table)
+        .deserialize(toml::Deserializer::new(content))
+        .ok()?;
+    let mut profiles = toml::from_str::<ProfileKeys>(content).ok()?.profiles;
+    let mut name = profile.to_owned();
+    // Profiles are removed as they are visited, so a cycle ends at an unknown profile.
+    while name != BASE_PROFILE {
+        let keys = profiles.remove(&name)?;
+        if let Some(val) = keys.get(key) {
+            return Some((
+                format!("profiles.{name}.{key}"),
+                line_of(content, &val.span()),
+            ));
+        }
+        name = match keys.get("inherits").map(|val| val.get_ref()) {
+            Some(Value::String(parent)) => parent.clone(),
+            _ => BASE_PROFILE.to_owned(),
+        };
+    }
+    let val = top_level.get(key)?;
+    Some((key.to_owned(), line_of(content, &val.span())))
+}
+
+/// Returns the names of the profiles in content, a TOML document, the base first.
+pub fn profiles(content: &str) -> Vec<String> {
+    let mut names = toml::from_str::<ProfileKeys>(content)
+        .map(|keys| keys.profiles.into_keys().collect::<Vec<String>>())
+        .unwrap_or_default();
+    names.sort();
+    names.retain(|name| name != BASE_PROFILE);
+    names.insert(0, BASE_PROFILE.to_owned());
+    names
+}
@@ -0,0 +1,410 @@
+//! Foxglove WebSocket protocol server, so runs can be inspected live in Foxglove Studio.
+//! See https://github.com/foxglove/ws-protocol for the protocol.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use opencv::core::{Mat, Vector};
+use opencv::imgcodecs::imencode;
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::{HeaderValue, StatusCode};
+use tungstenite::{Message, WebSocket};
+
+use crate::config::{self, ConfigError, Field};
+use crate::record;
+
+const SUBPROTOCOL: &str = "foxglove.websocket.v1";
+/// Opcode of binary message data frames.
+const MESSAGE_DATA: u8 = 0x01;
+/// Time between checks for client requests.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Messages queued for a client before newer ones are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// Schema of foxglove.CompressedImage, for JSON encoded images.
+const COMPRESSED_IMAGE_SCHEMA: &str = r#"{"type":"object","properties":{"timestamp":{"type":"object","properties":{"sec":{"type":"integer"},"nsec":{"type":"integer"}}},"frame_id":{"type":"string"},"data":{"type":"string","contentEncoding":"base64"},"format":{"type":"string"}}}"#;
+
+/// Topic published to clients.
+pub struct Channel {
+    pub id: u32,
+    pub topic: &'static str,
+    pub schema_name: &'static str,
+    pub schema: &'static str,
+}
+
+pub const RAW_IMAGE: Channel = Channel {
+    id: 1,
+    topic: "/camera/raw",
+    schema_name: "foxglove.CompressedImage",
+    schema: COMPRESSED_IMAGE_SCHEMA,
+};
+pub const OVERLAY_IMAGE: Channel = Channel {
+    id: 2,
+    topic: "/camera/overlay",
+    schema_name: "foxglove.CompressedImage",
+    schema: COMPRESSED_IMAGE_SCHEMA,
+};
+pub const LEFT_MASK: Channel = Channel {
+    id: 3,
+    topic: "/masks/left",
+    schema_name: "foxglove.CompressedImage",
+    schema: COMPRESSED_IMAGE_SCHEMA,
+};
+pub const RIGHT_MASK: Channel = Channel {
+    id: 4,
+    topic: "/masks/right",
+    schema_name: "foxglove.CompressedImage",
+    schema: COMPRESSED_IMAGE_SCHEMA,
+};
+pub const OBSTACLE_MASK: Channel = Channel {
+    id: 5,
+    topic: "/masks/obstacles",
+    schema_name: "foxglove.CompressedImage",
+    schema: COMPRESSED_IMAGE_SCHEMA,
+};
+pub const FINISH_MASK: Channel = Channel {
+    id: 6,
+    topic: "/masks/finish",
+    schema_name: "foxglove.CompressedImage",
+    schema: COMPRESSED_IMAGE_SCHEMA,
+};
+pub const ANGLE: Channel = Channel {
+    id: 7,
+    topic: "/angle",
+    schema_name: "immovable_object.Angle",
+    schema: r#"{"type":"object","properties":{"timestamp":{"type":"object","properties":{"sec":{"type":"integer"},"nsec":{"type":"integer"}}},"angle":{"type":"number"},"speed":{"type":"number"}}}"#,
+};
+pub const DUTY_CYCLE: Channel = Channel {
+    id: 8,
+    topic: "/duty_cycle",
+    schema_name: "immovable_object.DutyCycle",
+    schema: r#"{"type":"object","properties":{"timestamp":{"type":"object","properties":{"sec":{"type":"integer"},"nsec":{"type":"integer"}}},"left":{"type":"number"},"right":{"type":"number"}}}"#,
+};
+pub const DETECTIONS: Channel = Channel {
+    id: 9,
+    topic: "/detections",
+    schema_name: "immovable_object.Detections",
+    schema: r#"{"type":"object","properties":{"timestamp":{"type":"object","properties":{"sec":{"type":"integer"},"nsec":{"type":"integer"}}},"detections":{"type":"array","items":{"type":"object","properties":{"angle":{"type":"number"},"object":{"type":"string"},"distance":{"type":"integer"}}}}}}"#,
+};
+
+pub const DECISION: Channel = Channel {
+    id: 10,
+    topic: "/decision",
+    schema_name: "immovable_object.Decision",
+    schema: r#"{"type":"object","properties":{"timestamp":{"type":"object","properties":{"sec":{"type":"integer"},"nsec":{"type":"integer"}}},"chosen":{"type":"number"},"pid":{"type":"number"},"integral":{"type":"number"}}}"#,
+};
+pub const CONFIG: Channel = Channel {
+    id: 11,
+    topic: "/config",
+    schema_name: "immovable_object.Config",
+    schema: r#"{"type":"object","properties":{"timestamp":{"type":"object","properties":{"sec":{"type":"integer"},"nsec":{"type":"integer"}}},"toml":{"type":"string"}}}"#,
+};
+pub const THRESHOLDS: Channel = Channel {
+    id: 12,
+    topic: "/thresholds",
+    schema_name: "immovable_object.Thresholds",
+    schema: r#"{"type":"object","properties":{"timestamp":{"type":"object","properties":{"sec":{"type":"integer"},"nsec":{"type":"integer"}}},"classes":{"type":"array","items":{"type":"object","properties":{"class":{"type":"string"},"space":{"type":"string"},"ranges":{"type":"array","items":{"type":"object","properties":{"lower":{"type":"array","items":{"type":"integer"}},"upper":{"type":"array","items":{"type":"integer"}},"shift":{"type":"array","items":{"type":"integer"}}}}}}}}}}"#,
+};
+
+pub const CHANNELS: [&Channel; 12] = [
+    &RAW_IMAGE,
+    &OVERLAY_IMAGE,
+    &LEFT_MASK,
+    &RIGHT_MASK,
+    &OBSTACLE_MASK,
+    &FINISH_MASK,
+    &ANGLE,
+    &DUTY_CYCLE,
+    &DETECTIONS,
+    &DECISION,
+    &CONFIG,
+    &THRESHOLDS,
+];
+
+/// Connected client.
+struct Client {
+    id: u32,
+    /// Subscription ids by channel id.
+    subscriptions: HashMap<u32, u32>,
+    sender: SyncSender<Vec<u8>>,
+}
+
+/// Connected clients.
+static CLIENTS: Mutex<Vec<Client>> = Mutex::new(Vec::new());
+static NEXT_CLIENT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Requests clients can send.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum ClientOp {
+    Subscribe {
+        subscriptions: Vec<Subscription>,
+    },
+    Unsubscribe {
+        #[serde(rename = "subscriptionIds")]
+        subscription_ids: Vec<u32>,
+    },
+}
+
+#[derive(Deserialize)]
+struct Subscription {
+    id: u32,
+    #[serde(rename = "channelId")]
+    channel_id: u32,
+}
+
+/// Models the [foxglove] table of the config.
+pub struct FoxgloveConfig {
+    pub address: String,
+    pub port: u16,
+}
+
+/// Keys of the [foxglove] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct FoxgloveSchema {
+    address: Field<String>,
+    port: Field<i64>,
+}
+
+impl FoxgloveConfig {
+    /// Returns None if there is no [foxglove] table, i.e. the server is disabled.
+    pub fn from_toml(path: &str) -> Result<Option<Self>, Vec<ConfigError>> {
+        config::section(path, "foxglove", |schema: FoxgloveSchema, checker| Self {
+            address: checker
+                .get("foxglove.address", schema.address)
+                .map_or("0.0.0.0".to_string(), |(address, _)| address),
+            port: checker.int("foxglove.port", schema.port, 8765, 1..=u16::MAX as i64) as u16,
+        })
+    }
+}
+
+/// Returns the time in nanoseconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+/// Converts a timestamp from now into a Foxglove time.
+pub fn time(timestamp: u64) -> Json {
+    json!({
+        "sec": timestamp / 1_000_000_000,
+        "nsec": timestamp % 1_000_000_000,
+    })
+}
+
+/// Returns true if any client is subscribed to channel.
+pub fn subscribed(channel: &Channel) -> bool {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|client| client.subscriptions.contains_key(&channel.id))
+}
+
+/// Returns true if channel is subscribed to or being recorded,
+/// i.e. it is worth the effort of preparing messages for it.
+pub fn wanted(channel: &Channel) -> bool {
+    subscribed(channel) || record::recording()
+}
+
+/// Sends msg to every client subscribed to channel and records it.
+/// Clients that are not keeping up miss the message.
+pub fn publish(channel: &Channel, timestamp: u64, msg: &Json) {
+    let mut payload = None;
+    if record::recording() {
+        let payload = payload.get_or_insert_with(|| msg.to_string());
+        record::record(channel, timestamp, payload.as_bytes());
+    }
+    let clients = CLIENTS.lock().unwrap();
+    for client in clients.iter() {
+        let Some(subscription) = client.subscriptions.get(&channel.id) else {
+            continue;
+        };
+        let payload = payload.get_or_insert_with(|| msg.to_string());
+        let mut frame = Vec::with_capacity(13 + payload.len());
+        frame.push(MESSAGE_DATA);
+        frame.extend_from_slice(&subscription.to_le_bytes());
+        frame.extend_from_slice(&timestamp.to_le_bytes());
+        frame.extend_from_slice(payload.as_bytes());
+        let _ = client.sender.try_send(frame);
+    }
+}
+
+/// Encodes img with the given extension (.jpg or .png) and publishes it
+/// as a foxglove.CompressedImage, if anyone wants it.
+pub fn publish_image(channel: &Channel, timestamp: u64, img: &Mat, ext: &str) {
+    if !wanted(channel) {
+        return;
+    }
+    let mut buf = Vector::<u8>::new();
+    if let Err(err) = imencode(ext, img, &mut buf, &Vector::new()) {
+        log::warn!("Failed to encode {}: {err}", channel.topic);
+        return;
+    }
+    publish(
+        channel,
+        timestamp,
+        &json!({
+            "timestamp": time(timestamp),
+            "frame_id": "camera",
+            "format": ext.trim_start_matches('.').replace("jpg", "jpeg"),
+            "data": STANDARD.encode(buf.as_slice()),
+        }),
+    );
+}
+
+/// Serves the protocol on a background thread.
+pub fn serve(config: FoxgloveConfig) -> io::Result<()> {
+    let addr = format!("{}:{}", config.address, config.port);
+    let listener = TcpListener::bind(&addr)?;
+    log::info!("Serving Foxglove on ws://{addr}");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || handle_client(stream));
+                }
+                Err(err) => log::warn!("Failed to accept Foxglove client: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_client(stream: TcpStream) {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let ws = tungstenite::accept_hdr(stream, |req: &Request, mut res: Response| {
+        let offered = req
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|val| val.to_str().ok())
+            .flat_map(|val| val.split(','))
+            .any(|protocol| protocol.trim() == SUBPROTOCOL);
+        if !offered {
+            let mut err = ErrorResponse::new(Some(format!("Expected subprotocol {SUBPROTOCOL}")));
+            *err.status_mut() = StatusCode::BAD_REQUEST;
+            return Err(err);
+        }
+        res.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(SUBPROTOCOL),
+        );
+        Ok(res)
+    });
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(err) => {
+            log::warn!("Foxglove handshake with {peer} failed: {err}");
+            return;
+        }
+    };
+
+    let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+    CLIENTS.lock().unwrap().push(Client {
+        id,
+        subscriptions: HashMap::new(),
+        sender,
+    });
+    log::info!("Foxglove client {peer} connected.");
+    if let Err(err) = run_client(ws, id, receiver) {
+        log::warn!("Foxglove client {peer} failed: {err}");
+    }
+    CLIENTS.lock().unwrap().retain(|client| client.id != id);
+    log::info!("Foxglove client {peer} disconnected.");
+}
+
+fn run_client(
+    mut ws: WebSocket<TcpStream>,
+    id: u32,
+    receiver: Receiver<Vec<u8>>,
+) -> tungstenite::Result<()> {
+    ws.send(Message::Text(
+        json!({
+            "op": "serverInfo",
+            "name": "immovable-object",
+            "capabilities": [],
+            "supportedEncodings": [],
+            "metadata": {},
+        })
+        .to_string(),
+    ))?;
+    let channels = CHANNELS
+        .iter()
+        .map(|channel| {
+            json!({
+                "id": channel.id,
+                "topic": channel.topic,
+                "encoding": "json",
+                "schemaName": channel.schema_name,
+                "schema": channel.schema,
+            })
+        })
+        .collect::<Vec<Json>>();
+    ws.send(Message::Text(
+        json!({"op": "advertise", "channels": channels}).to_string(),
+    ))?;
+
+    ws.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    loop {
+        while let Ok(frame) = receiver.try_recv() {
+            ws.send(Message::Binary(frame))?;
+        }
+        match ws.read() {
+            Ok(Message::Text(text)) => handle_op(id, &text),
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(err))
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Updates a client's subscriptions.
+fn handle_op(id: u32, text: &str) {
+    let op = match serde_json::from_str::<ClientOp>(text) {
+        Ok(op) => op,
+        Err(err) => {
+            log::debug!("Ignoring Foxglove request {text}: {err}");
+            return;
+        }
+    };
+    let mut clients = CLIENTS.lock().unwrap();
+    let Some(client) = clients.iter_mut().find(|client| client.id == id) else {
+        return;
+    };
+    match op {
+        ClientOp::Subscribe { subscriptions } => {
+            for subscription in subscriptions {
+                client
+                    .subscriptions
+                    .insert(subscription.channel_id, subscription.id);
+            }
+        }
+        ClientOp::Unsubscribe { subscription_ids } => client
+            .subscriptions
+            .retain(|_, subscription| !subscription_ids.contains(subscription)),
+    }
+}
@@ -0,0 +1,538 @@
+//! Line protocol between the vision process and a motor daemon owning the car.
+//!
+//! The daemon listens on a Unix socket, `unix:/path/to.sock`, or on TCP,
+//! `host:port`. Each request is one line of ASCII and gets exactly one reply.
+//!
+//! | request                 | action                           |
+//! |-------------------------|----------------------------------|
+//! | `enable`                | `Drivable::enable`               |
+//! | `disable`               | `Drivable::disable`              |
+//! | `init`                  | `Drivable::init`                 |
+//! | `stop`                  | `Drivable::stop`                 |
+//! | `left <duty cycle>`     | `Drivable::drive_left`           |
+//! | `right <duty cycle>`    | `Drivable::drive_right`          |
+//! | `forward <speed>`       | `Drivable::forward`              |
+//! | `angle <angle> <speed>` | `Drivable::angle`                |
+//! | `status`                | nothing, just feeds the watchdog |
+//! | `auth <token>`          | first over TCP, see below        |
+//!
+//! Replies are `ok <enabled> <left> <right>`, where enabled is 0 or 1 and
+//! left and right are the duty cycles driving each motor, or `err <reason>`.
+//!
+//! Over TCP the first request must be `auth` with the token in [auth], and the
+//! daemon refuses to listen on TCP without one. Unix sockets are protected by
+//! their file permissions instead.
+//!
+//! The client that last sent a request other than `status` controls the car.
+//! When it disconnects, for any reason, the daemon stops and disables the car;
+//! other clients, e.g. one-off status probes, come and go freely. If the car is
+//! enabled and no request arrives from its controller within the watchdog timeout
+//! the daemon disables it too, so a crashed or hung vision process leaves the car
+//! stopped. Clients must enable the car again afterwards.
+//!
+//! For example, over a Unix socket with socat:
+//!
+//! ```text
+//! $ socat - UNIX-CONNECT:/tmp/immovable-object-motor.sock
+//! enable
+//! ok 1 0.01 0.01
+//! angle -30 40
+//! ok 1 0.0778 0.082
+//! fly
+//! err Unknown request: fly
+//! ```
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::auth::tokens_match;
+use crate::config::{self, ConfigError, Field};
+use crate::metrics;
+use crate::motor::{Drivable, Percent};
+use crate::path::Angle;
+
+/// Time the client waits for a reply. Enabling takes a couple of seconds.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the daemon listens.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Endpoint {
+    Unix(String),
+    Tcp(String),
+}
+
+impl Endpoint {
+    /// Parses `unix:<path>` or `<host>:<port>`.
+    pub fn parse(address: &str) -> Self {
+        match address.strip_prefix("unix:") {
+            Some(path) => Self::Unix(path.to_owned()),
+            None => Self::Tcp(address.to_owned()),
+        }
+    }
+
+    fn connect(&self) -> io::Result<Stream> {
+        let stream = match self {
+            Self::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+            Self::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+        };
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        Ok(stream)
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{path}"),
+            Self::Tcp(addr) => write!(f, "{addr}"),
+        }
+    }
+}
+
+/// Connection over either kind of socket.
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Self::Unix(stream) => Self::Unix(stream.try_clone()?),
+            Self::Tcp(stream) => Self::Tcp(stream.try_clone()?),
+        })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.flush(),
+            Self::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Models the [daemon] table of the config.
+pub struct DaemonConfig {
+    pub address: Endpoint,
+    /// Time the car may stay enabled without a request.
+    pub watchdog: Duration,
+}
+
+/// Keys of the [daemon] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct DaemonSchema {
+    address: Field<String>,
+    watchdog_ms: Field<i64>,
+}
+
+impl DaemonConfig {
+    /// Returns None if there is no [daemon] table, i.e. the car is driven in-process.
+    pub fn from_toml(path: &str) -> Result<Option<Self>, Vec<ConfigError>> {
+        config::section(path, "daemon", |schema: DaemonSchema, checker| {
+            let address = checker.get("daemon.address", schema.address);
+            if address.is_none() {
+                checker.error("daemon.address", None, "must be set");
+            }
+            let watchdog_ms =
+                checker.int("daemon.watchdog_ms", schema.watchdog_ms, 500, 1..=i64::MAX);
+            Self {
+                address: Endpoint::parse(&address.map(|(address, _)| address).unwrap_or_default()),
+                watchdog: Duration::from_millis(watchdog_ms as u64),
+            }
+        })
+    }
+}
+
+/// State of the car sent with every successful reply.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Status {
+    pub enabled: bool,
+    pub left_duty_cycle: f64,
+    pub right_duty_cycle: f64,
+}
+
+impl Status {
+    fn of(car: &impl Drivable) -> Self {
+        Self {
+            enabled: car.is_enabled(),
+            left_duty_cycle: metrics::LEFT_DUTY_CYCLE.get(),
+            right_duty_cycle: metrics::RIGHT_DUTY_CYCLE.get(),
+        }
+    }
+
+    /// Returns the reply line carrying this status, without the newline.
+    fn reply(&self) -> String {
+        format!(
+            "ok {} {} {}",
+            self.enabled as u8, self.left_duty_cycle, self.right_duty_cycle
+        )
+    }
+}
+
+/// Parses a reply line.
+pub fn parse_reply(line: &str) -> Result<Status, String> {
+    let line = line.trim_end();
+    if let Some(reason) = line.strip_prefix("err ") {
+        return Err(reason.to_owned());
+    }
+    let fields = line.split(' ').collect::<Vec<&str>>();
+    match fields[..] {
+        ["ok", enabled, left, right] => Ok(Status {
+            enabled: enabled == "1",
+            left_duty_cycle: left.parse().map_err(|_| format!("Bad reply: {line}"))?,
+            right_duty_cycle: right.parse().map_err(|_| format!("Bad reply: {line}"))?,
+        }),
+        _ => Err(format!("Bad reply: {line}")),
+    }
+}
+
+/// Carries out a request line on car.
+/// Returns the reply line, without the newline.
+pub fn handle_request(car: &mut impl Drivable, line: &str) -> String {
+    fn arg<T: std::str::FromStr>(arg: &str) -> Result<T, String> {
+        arg.parse().map_err(|_| format!("Bad argument: {arg}"))
+    }
+
+    let fields = line.split_whitespace().collect::<Vec<&str>>();
+    let result = match fields[..] {
+        ["enable"] => {
+            car.enable();
+            Ok(())
+        }
+        ["disable"] => {
+            car.disable();
+            Ok(())
+        }
+        ["init"] => {
+            car.init();
+            Ok(())
+        }
+        ["stop"] => {
+            car.stop();
+            Ok(())
+        }
+        ["left", duty_cycle] => arg(duty_cycle).map(|duty_cycle| car.drive_left(duty_cycle)),
+        ["right", duty_cycle] => arg(duty_cycle).map(|duty_cycle| car.drive_right(duty_cycle)),
+        ["forward", speed] => arg(speed).map(|speed| car.forward(speed)),
+        ["angle", angle, speed] => {
+            arg(angle).and_then(|angle| arg(speed).map(|speed| car.angle(angle, speed)))
+        }
+        ["status"] => Ok(()),
+        _ => Err(format!("Unknown request: {line}")),
+    };
+    match result {
+        Ok(()) => Status::of(car).reply(),
+        Err(reason) => format!("err {reason}"),
+    }
+}
+
+/// Car shared between the daemon's clients.
+struct Shared<T> {
+    car: Mutex<T>,
+    /// Time the controlling client last sent a request.
+    last_request: Mutex<Instant>,
+    /// Id of the client controlling the car, if any.
+    controller: Mutex<Option<u64>>,
+}
+
+/// Serves car at config.address until the listener fails.
+/// Clients connecting over TCP must present token, so TCP is refused without one.
+/// Each client is handled on its own thread.
+pub fn serve<T: Drivable>(car: T, config: DaemonConfig, token: Option<String>) -> io::Result<()> {
+    let shared = Arc::new(Shared {
+        car: Mutex::new(car),
+        last_request: Mutex::new(Instant::now()),
+        controller: Mutex::new(None),
+    });
+    watch(shared.clone(), config.watchdog);
+    let ids = AtomicU64::new(0);
+
+    log::info!("Serving motors on {}", config.address);
+    match &config.address {
+        Endpoint::Unix(path) => {
+            // Remove the socket left behind by a previous run.
+            if fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            for stream in listener.incoming() {
+                let stream = Stream::Unix(stream?);
+                let id = ids.fetch_add(1, Ordering::Relaxed);
+                spawn_client(stream, id, None, shared.clone());
+            }
+        }
+        Endpoint::Tcp(addr) => {
+            let Some(token) = token.filter(|token| !token.is_empty()) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Serving motors over TCP requires a token in [auth], use a Unix socket otherwise",
+                ));
+            };
+            let listener = TcpListener::bind(addr)?;
+            for stream in listener.incoming() {
+                let stream = stream?;
+                stream.set_nodelay(true)?;
+                let id = ids.fetch_add(1, Ordering::Relaxed);
+                spawn_client(Stream::Tcp(stream), id, Some(token.clone()), shared.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Disables the car whenever it is enabled and no request
+/// has been received from its controller for longer than timeout.
+fn watch<T: Drivable>(shared: Arc<Shared<T>>, timeout: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(timeout / 4);
+        // Requests update last_request while holding the car,
+        // so a slow request is never mistaken for a lapse.
+        let mut car = lock(&shared.car);
+        if car.is_enabled() && shared.last_request.lock().unwrap().elapsed() > timeout {
+            log::error!("No request for {}ms, disabling...", timeout.as_millis());
+            car.disable();
+        }
+    });
+}
+
+/// Locks the car even if a thread panicked while driving it,
+/// so it can always be stopped.
+fn lock<T>(car: &Mutex<T>) -> MutexGuard<T> {
+    car.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Stops and disables the car when dropped, i.e. when its client
+/// disconnects or the thread handling it panics, if that client controls it.
+struct StopOnDisconnect<'a, T: Drivable> {
+    id: u64,
+    shared: &'a Shared<T>,
+}
+
+impl<T: Drivable> Drop for StopOnDisconnect<'_, T> {
+    fn drop(&mut self) {
+        let mut car = lock(&self.shared.car);
+        let mut controller = lock(&self.shared.controller);
+        if *controller != Some(self.id) {
+            log::info!("Client {} disconnected.", self.id);
+            return;
+        }
+        log::info!("Controlling client {} disconnected, stopping...", self.id);
+        *controller = None;
+        car.stop();
+        if car.is_enabled() {
+            car.disable();
+        }
+    }
+}
+
+fn spawn_client<T: Drivable>(
+    stream: Stream,
+    id: u64,
+    token: Option<String>,
+    shared: Arc<Shared<T>>,
+) {
+    thread::spawn(move || {
+        log::info!("Client {id} connected.");
+        if let Err(err) = handle_client(stream, id, token.as_deref(), &shared) {
+            log::warn!("Client {id} failed: {err}");
+        }
+    });
+}
+
+/// Serves requests from client id until it disconnects.
+/// Its first request must be auth with token if token is Some.
+fn handle_client<T: Drivable>(
+    stream: Stream,
+    id: u64,
+    token: Option<&str>,
+    shared: &Shared<T>,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut lines = BufReader::new(stream).lines();
+    if let Some(token) = token {
+        let line = lines.next().transpose()?.unwrap_or_default();
+        let authorised = line
+            .strip_prefix("auth ")
+            .is_some_and(|presented| tokens_match(token, presented.trim_end()));
+        if !authorised {
+            writeln!(writer, "err Unauthorised")?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "did not present the token",
+            ));
+        }
+        let status = Status::of(&*lock(&shared.car));
+        writeln!(writer, "{}", status.reply())?;
+    }
+
+    let _stop = StopOnDisconnect { id, shared };
+    for line in lines {
+        let line = line?;
+        let reply = {
+            let mut car = lock(&shared.car);
+            let reply = handle_request(&mut *car, &line);
+            let mut controller = lock(&shared.controller);
+            if line.trim() != "status" {
+                *controller = Some(id);
+            }
+            if *controller == Some(id) {
+                *shared.last_request.lock().unwrap() = Instant::now();
+            }
+            reply
+        };
+        writeln!(writer, "{reply}")?;
+    }
+    Ok(())
+}
+
+/// Car driven by a motor daemon, possibly on another machine.
+///
+/// Requests that fail are logged and the car is assumed disabled;
+/// the connection is reopened on the next request.
+pub struct RemoteCar {
+    address: Endpoint,
+    /// Token presented to the daemon over TCP.
+    token: Option<String>,
+    connection: Option<(BufReader<Stream>, Stream)>,
+    /// Status from the last reply.
+    status: Status,
+}
+
+impl RemoteCar {
+    /// Connects to the daemon at address, presenting token if it is over TCP.
+    pub fn connect(address: Endpoint, token: Option<String>) -> io::Result<Self> {
+        let mut car = Self {
+            address,
+            token,
+            connection: None,
+            status: Status {
+                enabled: false,
+                left_duty_cycle: 0.0,
+                right_duty_cycle: 0.0,
+            },
+        };
+        car.status = car.try_request("status")?;
+        Ok(car)
+    }
+
+    fn try_request(&mut self, line: &str) -> io::Result<Status> {
+        if self.connection.is_none() {
+            let stream = self.address.connect()?;
+            self.connection = Some((BufReader::new(stream.try_clone()?), stream));
+            if let (Endpoint::Tcp(_), Some(token)) = (&self.address, self.token.clone()) {
+                if let Err(err) = self.try_request(&format!("auth {token}")) {
+                    self.connection = None;
+                    return Err(err);
+                }
+            }
+        }
+        let (reader, writer) = self.connection.as_mut().unwrap();
+        let result = writeln!(writer, "{line}").and_then(|_| {
+            let mut reply = String::new();
+            match reader.read_line(&mut reply)? {
+                0 => Err(io::ErrorKind::UnexpectedEof.into()),
+                _ => Ok(reply),
+            }
+        });
+        match result {
+            Ok(reply) => {
+                parse_reply(&reply).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+            }
+            Err(err) => {
+                self.connection = None;
+                Err(err)
+            }
+        }
+    }
+
+    fn request(&mut self, line: &str) {
+        match self.try_request(line) {
+            Ok(status) => self.status = status,
+            Err(err) => {
+                log::error!("Motor daemon at {}: {line} failed: {err}", self.address);
+                self.status.enabled = false;
+            }
+        }
+        metrics::LEFT_DUTY_CYCLE.set(self.status.left_duty_cycle);
+        metrics::RIGHT_DUTY_CYCLE.set(self.status.right_duty_cycle);
+    }
+}
+
+impl Drivable for RemoteCar {
+    fn enable(&mut self) {
+        self.request("enable");
+    }
+
+    fn disable(&mut self) {
+        self.request("disable");
+    }
+
+    /// Returns whether the car was enabled as of the last request.
+    fn is_enabled(&self) -> bool {
+        self.status.enabled
+    }
+
+    fn drive_left(&mut self, duty_cycle: f64) {
+        self.request(&format!("left {duty_cycle}"));
+    }
+
+    fn drive_right(&mut self, duty_cycle: f64) {
+        self.request(&format!("right {duty_cycle}"));
+    }
+
+    fn init(&mut self) {
+        self.request("init");
+    }
+
+    fn stop(&mut self) {
+        self.request("stop");
+    }
+
+    fn forward(&mut self, speed: Percent) {
+        self.request(&format!("forward {speed}"));
+    }
+
+    fn angle(&mut self, angle: Angle, speed: Percent) {
+        self.request(&format!("angle {angle} {speed}"));
+    }
+}
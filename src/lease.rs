@@ -0,0 +1,190 @@
+use std::future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use gotham::handler::HandlerFuture;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::{Body, HeaderMap, Method, Response, StatusCode, Uri};
+use gotham::middleware::Middleware;
+use gotham::mime;
+use gotham::prelude::*;
+use gotham::state::State;
+
+use crate::remote::cookie;
+
+/// Name of the cookie/header clients identify themselves with.
+pub const CONTROLLER_COOKIE: &str = "controller";
+pub const CONTROLLER_HEADER: &str = "x-controller";
+
+/// Time a lease lasts without any commands from its holder.
+const LEASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Paths anyone may POST to regardless of who holds control.
+/// Heartbeats are not among them, so only the holder can keep the car alive.
+const UNLEASED_PATHS: [&str; 2] = ["/stop", "/api/lease/takeover"];
+
+/// Exclusive right of one client to control the car.
+struct Lease {
+    holder: String,
+    expires: Instant,
+}
+
+/// Outcome of trying to claim control.
+pub enum Claim {
+    Granted,
+    /// Control is held by another client.
+    Held(String),
+}
+
+/// Tracks which client currently controls the car.
+#[derive(Clone, Default, StateData)]
+pub struct LeaseControl {
+    inner: Arc<Mutex<Option<Lease>>>,
+}
+
+impl LeaseControl {
+    fn inner(&self) -> MutexGuard<Option<Lease>> {
+        self.inner.lock().unwrap()
+    }
+
+    /// Gives control to client if it is free, expired or already theirs,
+    /// or unconditionally if takeover is true.
+    /// Extends the lease on success.
+    pub fn claim(&self, client: &str, takeover: bool) -> Claim {
+        self.claim_at(client, takeover, Instant::now())
+    }
+
+    /// Claims control as if it were now.
+    pub fn claim_at(&self, client: &str, takeover: bool, now: Instant) -> Claim {
+        let mut lease = self.inner();
+        if let Some(current) = lease.as_ref() {
+            if !takeover && current.holder != client && current.expires > now {
+                return Claim::Held(current.holder.clone());
+            }
+        }
+        *lease = Some(Lease {
+            holder: client.to_owned(),
+            expires: now + LEASE_TIMEOUT,
+        });
+        Claim::Granted
+    }
+
+    /// Gives up control if client holds it.
+    pub fn release(&self, client: &str) {
+        let mut lease = self.inner();
+        if lease
+            .as_ref()
+            .is_some_and(|current| current.holder == client)
+        {
+            *lease = None;
+        }
+    }
+
+    /// Returns the current holder and the time left on their lease.
+    pub fn holder(&self) -> Option<(String, Duration)> {
+        self.holder_at(Instant::now())
+    }
+
+    /// Returns the holder as if it were now.
+    pub fn holder_at(&self, now: Instant) -> Option<(String, Duration)> {
+        self.inner().as_ref().and_then(|current| {
+            current
+                .expires
+                .checked_duration_since(now)
+                .map(|remaining| (current.holder.clone(), remaining))
+        })
+    }
+}
+
+/// Returns the name the requesting client identifies itself with.
+pub fn client_name(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(CONTROLLER_HEADER)
+        .and_then(|val| val.to_str().ok())
+        .or_else(|| cookie(headers, CONTROLLER_COOKIE))
+        .filter(|name| !name.is_empty())
+}
+
+/// Rejects commands from clients that do not hold control.
+#[derive(Clone, NewMiddleware)]
+pub struct LeaseMiddleware {
+    lease: LeaseControl,
+}
+
+impl LeaseMiddleware {
+    pub fn new(lease: LeaseControl) -> Self {
+        Self { lease }
+    }
+}
+
+impl Middleware for LeaseMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+        Self: Sized,
+    {
+        let path = Uri::borrow_from(&state).path();
+        if matches!(*Method::borrow_from(&state), Method::GET | Method::HEAD)
+            || UNLEASED_PATHS.contains(&path)
+        {
+            return chain(state);
+        }
+
+        let rejection = match client_name(HeaderMap::borrow_from(&state)) {
+            None => Some((
+                StatusCode::BAD_REQUEST,
+                "Set a controller name on the landing page before sending commands.".to_string(),
+            )),
+            Some(client) => match self.lease.claim(client, false) {
+                Claim::Granted => None,
+                Claim::Held(holder) => Some((
+                    StatusCode::CONFLICT,
+                    format!("Control is held by {holder}, take over to send commands."),
+                )),
+            },
+        };
+
+        match rejection {
+            None => chain(state),
+            Some((status, msg)) => {
+                let res = create_response(&state, status, mime::TEXT_PLAIN, msg);
+                Box::pin(future::ready(Ok((state, res))))
+            }
+        }
+    }
+}
+
+pub fn lease_status(state: State) -> (State, String) {
+    let status = match LeaseControl::borrow_from(&state).holder() {
+        Some((holder, remaining)) => {
+            format!("Controlled by {holder} ({}s left)", remaining.as_secs())
+        }
+        None => "No one has control".to_string(),
+    };
+    (state, status)
+}
+
+pub fn take_over(state: State) -> (State, Response<Body>) {
+    let res = match client_name(HeaderMap::borrow_from(&state)) {
+        Some(client) => {
+            log::info!("{client} took control.");
+            LeaseControl::borrow_from(&state).claim(client, true);
+            create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, "Took control")
+        }
+        None => create_response(
+            &state,
+            StatusCode::BAD_REQUEST,
+            mime::TEXT_PLAIN,
+            "Set a controller name on the landing page to take control.",
+        ),
+    };
+    (state, res)
+}
+
+pub fn release(state: State) -> (State, String) {
+    if let Some(client) = client_name(HeaderMap::borrow_from(&state)) {
+        LeaseControl::borrow_from(&state).release(client);
+    }
+    (state, "Released".to_string())
+}
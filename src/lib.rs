@@ -0,0 +1,21 @@
+pub mod adaptive;
+pub mod assets;
+pub mod auth;
+pub mod calibrate;
+pub mod camera;
+pub mod cleanup;
+pub mod config;
+pub mod foxglove;
+pub mod ipc;
+pub mod lease;
+pub mod logging;
+pub mod metrics;
+pub mod motor;
+pub mod path;
+pub mod perspective;
+pub mod record;
+pub mod remote;
+pub mod snapshot;
+#[allow(dead_code)]
+mod tests;
+pub mod udp;
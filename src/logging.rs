@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use gotham::hyper::body::{Bytes, Sender};
+use gotham::hyper::Body;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+
+use crate::config::{self, Checker, ConfigError, Field};
+
+/// Prefix of targets from this crate, stripped so targets are just the module name.
+const CRATE_PREFIX: &str = "immovable_object::";
+
+/// Bodies of open event streams.
+static SUBSCRIBERS: Mutex<Vec<Sender>> = Mutex::new(Vec::new());
+
+/// Models the [log] table of the config.
+pub struct LogConfig {
+    /// Level for targets without their own level.
+    pub level: LevelFilter,
+    /// Levels for specific targets (motor, path, remote...).
+    pub targets: HashMap<String, LevelFilter>,
+    /// File to write logs to, if any.
+    pub file: Option<String>,
+    /// Size the log file may reach before it is rotated.
+    pub max_bytes: u64,
+    /// Number of rotated log files to keep.
+    pub keep: u32,
+}
+
+/// Keys of the [log] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct LogSchema {
+    level: Field<String>,
+    targets: HashMap<String, Field<String>>,
+    file: Field<String>,
+    max_bytes: Field<i64>,
+    keep: Field<i64>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+            targets: HashMap::new(),
+            file: None,
+            max_bytes: 1_000_000,
+            keep: 5,
+        }
+    }
+}
+
+impl LogConfig {
+    /// Returns the defaults if there is no [log] table.
+    pub fn from_toml(path: &str) -> Result<Self, Vec<ConfigError>> {
+        let config = config::section(path, "log", |schema: LogSchema, checker| {
+            let default = Self::default();
+            let mut targets = HashMap::new();
+            for (target, level) in schema.targets {
+                let key = format!("log.targets.{target}");
+                if let Some(level) = Self::level(checker, &key, level) {
+                    targets.insert(target, level);
+                }
+            }
+            Self {
+                level: Self::level(checker, "log.level", schema.level).unwrap_or(default.level),
+                targets,
+                file: checker.get("log.file", schema.file).map(|(file, _)| file),
+                max_bytes: checker.int(
+                    "log.max_bytes",
+                    schema.max_bytes,
+                    default.max_bytes as i64,
+                    1..=i64::MAX,
+                ) as u64,
+                keep: checker.int(
+                    "log.keep",
+                    schema.keep,
+                    default.keep as i64,
+                    0..=u32::MAX as i64,
+                ) as u32,
+            }
+        })?;
+        Ok(config.unwrap_or_default())
+    }
+
+    fn level(checker: &mut Checker, key: &str, field: Field<String>) -> Option<LevelFilter> {
+        let (level, line) = checker.get(key, field)?;
+        let level = level.parse::<LevelFilter>().ok();
+        if level.is_none() {
+            checker.error(
+                key,
+                Some(line),
+                "must be one of off, error, warn, info, debug, trace",
+            );
+        }
+        level
+    }
+}
+
+/// Log file that is renamed to file.1, file.2... once it gets too big.
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_bytes: u64, keep: u32) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size,
+            max_bytes,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let _ = fs::rename(
+                    format!("{}.{i}", self.path),
+                    format!("{}.{}", self.path, i + 1),
+                );
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Writes log records to stderr, the log file and any open event streams.
+pub struct Logger {
+    config: LogConfig,
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl Logger {
+    /// Installs a logger with the given config as the global logger.
+    pub fn init(config: LogConfig) {
+        let file = config.file.as_ref().map(|path| {
+            Mutex::new(
+                RotatingFile::open(path, config.max_bytes, config.keep)
+                    .unwrap_or_else(|err| panic!("Failed to open log file {path}: {err}")),
+            )
+        });
+        log::set_boxed_logger(Box::new(Logger { config, file })).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let target = short_target(target);
+        self.config
+            .targets
+            .get(target)
+            .copied()
+            .unwrap_or(self.config.level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let target = short_target(record.target());
+        let line = format!(
+            "{timestamp:.3} {:<5} {target}: {}",
+            record.level(),
+            record.args()
+        );
+        eprintln!("{line}");
+        if let Some(file) = &self.file {
+            if let Err(err) = file.lock().unwrap().write_line(&line) {
+                eprintln!("Failed to write to log file: {err}");
+            }
+        }
+        broadcast("log", &line);
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().file.flush();
+        }
+    }
+}
+
+/// Returns the module a target refers to, without the crate name.
+fn short_target(target: &str) -> &str {
+    target.strip_prefix(CRATE_PREFIX).unwrap_or(target)
+}
+
+/// Sends an event to every open event stream.
+/// Streams that are closed or not keeping up are dropped.
+pub fn broadcast(event: &str, data: &str) {
+    let mut msg = format!("event: {event}\n");
+    for line in data.lines() {
+        msg.push_str(&format!("data: {line}\n"));
+    }
+    msg.push('\n');
+
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    subscribers.retain_mut(|sender| sender.try_send_data(Bytes::from(msg.clone())).is_ok());
+}
+
+/// Logs a change in the car's state, which reaches open event streams as a log line,
+/// and sends it as a state event for clients showing the current state.
+pub fn state_transition(state: &str) {
+    log::info!(target: "state", "{state}");
+    broadcast("state", state);
+}
+
+/// Opens a new event stream.
+/// Returns the body to send to the client.
+pub fn subscribe() -> Body {
+    let (sender, body) = Body::channel();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    body
+}
@@ -1,33 +1,180 @@
-mod motor;
-mod path;
-mod remote;
-#[allow(dead_code)]
-mod tests;
-
 use opencv::{
     core::Size,
-    videoio::{VideoWriter, VideoCapture, CAP_ANY, CAP_PROP_BUFFERSIZE},
+    videoio::{VideoCapture, CAP_ANY, CAP_PROP_BUFFERSIZE, CAP_PROP_FRAME_HEIGHT, CAP_PROP_FRAME_WIDTH},
     prelude::*
 };
+use std::process::exit;
 use std::thread;
-use path::{DrivableConfig, Pathfinder};
-use remote::CarControl;
-use motor::Car;
+use immovable_object::auth::AuthConfig;
+use immovable_object::config::{self, ConfigError};
+use immovable_object::foxglove::{self, FoxgloveConfig};
+use immovable_object::ipc::{DaemonConfig, RemoteCar};
+use immovable_object::lease::LeaseControl;
+use immovable_object::logging::{LogConfig, Logger};
+use immovable_object::path::{DebugVideo, DrivableConfig, Pathfinder, VisionConfig};
+use immovable_object::record::RecordConfig;
+use immovable_object::remote::{self, CarControl, ConfigControl, RemoteConfig};
+use immovable_object::snapshot::SnapshotControl;
+use immovable_object::udp::{self, UdpConfig};
+use immovable_object::motor::{Car, Drivable};
+
+const USAGE: &str = "Usage: immovable-object [--profile <name>] [--address <address>] \
+    [--port <port>] [--tls-cert <path>] [--tls-key <path>]
+       immovable-object check-config [<path>]";
+
+/// Prints msg and how to run the program, then exits.
+fn usage(msg: &str) -> ! {
+    eprintln!("{msg}\n{USAGE}");
+    exit(2);
+}
 
 fn main() {
-    let car = CarControl::new(Car::default());
+    let mut args = std::env::args().skip(1).collect::<Vec<String>>();
+    if args.first().is_some_and(|arg| arg == "check-config") {
+        let path = args.get(1).map(String::as_str).unwrap_or("thresholds.toml");
+        exit(if check_config(path) { 0 } else { 1 });
+    }
+
+    Logger::init(read(LogConfig::from_toml));
+    // Taken out before the remote reads the rest of the arguments.
+    let profile = args.iter().position(|arg| arg == "--profile").map(|i| {
+        let name = args
+            .get(i + 1)
+            .cloned()
+            .unwrap_or_else(|| usage("Expected a value after --profile"));
+        args.drain(i..=i + 1);
+        name
+    });
+    let config = config::or_exit(
+        "thresholds.toml",
+        ConfigControl::from_toml("thresholds.toml", profile.as_deref()),
+    );
+    match read(DaemonConfig::from_toml) {
+        Some(daemon) => {
+            let token = read(AuthConfig::from_toml).token;
+            let car = RemoteCar::connect(daemon.address.clone(), token).unwrap_or_else(|err| {
+                panic!("Failed to connect to motor daemon at {}: {err}", daemon.address)
+            });
+            run(car, config, &args)
+        }
+        None => run(Car::default(), config, &args),
+    }
+}
+
+/// Reads a section of thresholds.toml, exiting if it has any problems.
+fn read<T>(section: fn(&str) -> Result<T, Vec<ConfigError>>) -> T {
+    config::or_exit("thresholds.toml", section("thresholds.toml"))
+}
+
+/// Checks every section of the config at path, printing any problems.
+/// Returns true if it is valid.
+fn check_config(path: &str) -> bool {
+    // Profiles share keys, so the same problem can be found in each.
+    let mut errors = vec![];
+    let profiles = DrivableConfig::profiles(path);
+    let chosen = std::iter::once(None).chain(profiles.iter().map(|name| Some(name.as_str())));
+    for profile in chosen {
+        if let Err(profile_errors) = DrivableConfig::from_toml(path, profile) {
+            for err in profile_errors {
+                if !errors.contains(&err) {
+                    errors.push(err);
+                }
+            }
+        }
+    }
+    // The other sections would only report the same syntax errors.
+    let readable = errors.iter().all(|err| !err.key.is_empty());
+    let sections: [fn(&str) -> Result<(), Vec<ConfigError>>; 9] = [
+        |path| LogConfig::from_toml(path).map(drop),
+        |path| AuthConfig::from_toml(path).map(drop),
+        |path| remote::heartbeat_timeout(path).map(drop),
+        |path| RemoteConfig::from_toml(path).map(drop),
+        |path| UdpConfig::from_toml(path).map(drop),
+        |path| DaemonConfig::from_toml(path).map(drop),
+        |path| FoxgloveConfig::from_toml(path).map(drop),
+        |path| RecordConfig::from_toml(path).map(drop),
+        |path| VisionConfig::from_toml(path).map(drop),
+    ];
+    if readable {
+        for check in sections {
+            if let Err(section_errors) = check(path) {
+                errors.extend(section_errors);
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        println!("{path} is valid, with profiles {}.", profiles.join(", "));
+    } else {
+        errors.sort_by_key(|err| err.line);
+        eprintln!("{}", config::report(path, &errors));
+    }
+    errors.is_empty()
+}
+
+fn run<T: Drivable>(car: T, config: ConfigControl, args: &[String]) {
+    let car = CarControl::new(car);
+    if let Some(timeout) = read(remote::heartbeat_timeout) {
+        car.watch_heartbeat(timeout);
+    }
     let clone = car.clone();
-    let debug_out = VideoWriter::new(
-        "vision.mp4",
-        VideoWriter::fourcc('m', 'p', '4', 'v').unwrap(),
-        30.0,
-        Size::new(640, 250),
-        true,
-    )
-    .unwrap();
-    thread::spawn(|| remote::serve(clone));
+    if let Err(err) = config.watch() {
+        log::warn!("Config will not be reloaded when thresholds.toml changes: {err}");
+    }
+    let config_clone = config.clone();
+    let auth = read(AuthConfig::from_toml);
+    // Remote and UDP clients take control of the car from each other.
+    let lease = LeaseControl::default();
+    if let Some(udp_config) = read(UdpConfig::from_toml) {
+        if let Err(err) = udp::serve(car.clone(), udp_config, auth.token.clone(), lease.clone()) {
+            panic!("Failed to start UDP control: {err}");
+        }
+    }
+    if let Some(foxglove_config) = read(FoxgloveConfig::from_toml) {
+        if let Err(err) = foxglove::serve(foxglove_config) {
+            panic!("Failed to start Foxglove server: {err}");
+        }
+    }
+    let snapshots = SnapshotControl::new();
+    let snapshots_clone = snapshots.clone();
+    let mut remote_config = read(RemoteConfig::from_toml);
+    if let Err(err) = remote_config.apply_args(args) {
+        usage(&err);
+    }
+    let roi = config.get().roi;
+    // Masks are seen from above if there is a perspective, so the debug video is that size.
+    let vision = read(VisionConfig::from_toml);
+    let debug_size = vision
+        .perspective
+        .as_ref()
+        .map(|perspective| perspective.size())
+        .unwrap_or(Size::new(roi.width, roi.height));
+    let debug_out = DebugVideo::new("vision.mp4", debug_size).unwrap();
+    thread::spawn(|| {
+        if let Err(err) = remote::serve(clone, config_clone, auth, snapshots_clone, remote_config, lease) {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    });
 
     let mut cap = VideoCapture::new(0, CAP_ANY).unwrap();
     cap.set(CAP_PROP_BUFFERSIZE, 1.0).unwrap();
-    Pathfinder::new(car, DrivableConfig::from_toml("thresholds.toml"), Some(debug_out)).drive(cap);
+    let resolution = (
+        cap.get(CAP_PROP_FRAME_WIDTH).unwrap() as i32,
+        cap.get(CAP_PROP_FRAME_HEIGHT).unwrap() as i32,
+    );
+    if resolution.0 <= 0 || resolution.1 <= 0 {
+        log::warn!("Camera did not report its resolution, so the roi is not checked against it.");
+    } else {
+        config::or_exit("thresholds.toml", config.set_resolution(resolution));
+    }
+    Pathfinder::new(
+        car,
+        config,
+        Some(debug_out),
+        snapshots,
+        read(RecordConfig::from_toml),
+        vision,
+    )
+    .drive(cap);
 }
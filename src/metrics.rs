@@ -0,0 +1,212 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::path::TrackObject;
+
+/// Monotonically increasing count.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, val: u64) {
+        self.0.fetch_add(val, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value that can go up and down.
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn set(&self, val: f64) {
+        self.0.store(val.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Distribution of observed values over fixed buckets.
+pub struct Histogram {
+    /// Upper bounds of the buckets, ascending.
+    bounds: &'static [f64],
+    /// Cumulative count for each bound, the sum and the total count.
+    data: Mutex<(Vec<u64>, f64, u64)>,
+}
+
+impl Histogram {
+    pub const fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            data: Mutex::new((Vec::new(), 0.0, 0)),
+        }
+    }
+
+    pub fn observe(&self, val: f64) {
+        let mut data = self.data.lock().unwrap();
+        if data.0.is_empty() {
+            data.0 = vec![0; self.bounds.len()];
+        }
+        for (bound, count) in self.bounds.iter().zip(data.0.iter_mut()) {
+            if val <= *bound {
+                *count += 1;
+            }
+        }
+        data.1 += val;
+        data.2 += 1;
+    }
+
+    /// Writes the bucket, sum and count lines of the histogram.
+    pub fn render(&self, out: &mut String, name: &str) {
+        let data = self.data.lock().unwrap();
+        for (i, bound) in self.bounds.iter().enumerate() {
+            let count = data.0.get(i).copied().unwrap_or(0);
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}").unwrap();
+        }
+        writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", data.2).unwrap();
+        writeln!(out, "{name}_sum {}", data.1).unwrap();
+        writeln!(out, "{name}_count {}", data.2).unwrap();
+    }
+}
+
+pub static FRAMES_PROCESSED: Counter = Counter::new();
+pub static FRAMES_DROPPED: Counter = Counter::new();
+pub static FRAME_LATENCY: Histogram =
+    Histogram::new(&[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]);
+pub static RAYS_CAST: Histogram = Histogram::new(&[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0]);
+pub static LEFT_LINE_DETECTIONS: Counter = Counter::new();
+pub static RIGHT_LINE_DETECTIONS: Counter = Counter::new();
+pub static OBSTACLE_DETECTIONS: Counter = Counter::new();
+pub static FINISH_LINE_DETECTIONS: Counter = Counter::new();
+pub static ANGLE: Gauge = Gauge::new();
+pub static SPEED: Gauge = Gauge::new();
+pub static LEFT_DUTY_CYCLE: Gauge = Gauge::new();
+pub static RIGHT_DUTY_CYCLE: Gauge = Gauge::new();
+pub static ENABLES: Counter = Counter::new();
+pub static DISABLES: Counter = Counter::new();
+
+/// Counts a ray hitting a track object.
+pub fn detection(obj: &TrackObject) {
+    match obj {
+        TrackObject::LeftLine(_) => LEFT_LINE_DETECTIONS.inc(),
+        TrackObject::RightLine(_) => RIGHT_LINE_DETECTIONS.inc(),
+        TrackObject::Obstacle(_) => OBSTACLE_DETECTIONS.inc(),
+        TrackObject::FinishLine(_) => FINISH_LINE_DETECTIONS.inc(),
+    }
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let counters = [
+        (
+            "io_frames_processed_total",
+            "Frames passed to consider_frame.",
+            &FRAMES_PROCESSED,
+        ),
+        (
+            "io_frames_dropped_total",
+            "Camera frames missed while processing.",
+            &FRAMES_DROPPED,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter").unwrap();
+        writeln!(out, "{name} {}", counter.get()).unwrap();
+    }
+
+    writeln!(
+        out,
+        "# HELP io_consider_frame_seconds Time taken by consider_frame.\n\
+         # TYPE io_consider_frame_seconds histogram"
+    )
+    .unwrap();
+    FRAME_LATENCY.render(&mut out, "io_consider_frame_seconds");
+
+    writeln!(
+        out,
+        "# HELP io_rays_cast Rays cast by choose_angle per frame.\n\
+         # TYPE io_rays_cast histogram"
+    )
+    .unwrap();
+    RAYS_CAST.render(&mut out, "io_rays_cast");
+
+    writeln!(
+        out,
+        "# HELP io_detections_total Rays that hit each kind of track object.\n\
+         # TYPE io_detections_total counter"
+    )
+    .unwrap();
+    for (object, counter) in [
+        ("left_line", &LEFT_LINE_DETECTIONS),
+        ("right_line", &RIGHT_LINE_DETECTIONS),
+        ("obstacle", &OBSTACLE_DETECTIONS),
+        ("finish_line", &FINISH_LINE_DETECTIONS),
+    ] {
+        writeln!(
+            out,
+            "io_detections_total{{object=\"{object}\"}} {}",
+            counter.get()
+        )
+        .unwrap();
+    }
+
+    for (name, help, gauge) in [
+        (
+            "io_angle",
+            "Angle the car was last told to drive at.",
+            &ANGLE,
+        ),
+        (
+            "io_speed",
+            "Speed the car was last told to drive at.",
+            &SPEED,
+        ),
+    ] {
+        writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge").unwrap();
+        writeln!(out, "{name} {}", gauge.get()).unwrap();
+    }
+
+    writeln!(
+        out,
+        "# HELP io_duty_cycle Duty cycle currently driving each motor.\n\
+         # TYPE io_duty_cycle gauge"
+    )
+    .unwrap();
+    for (motor, gauge) in [("left", &LEFT_DUTY_CYCLE), ("right", &RIGHT_DUTY_CYCLE)] {
+        writeln!(out, "io_duty_cycle{{motor=\"{motor}\"}} {}", gauge.get()).unwrap();
+    }
+
+    writeln!(
+        out,
+        "# HELP io_transitions_total Times the car was enabled or disabled.\n\
+         # TYPE io_transitions_total counter"
+    )
+    .unwrap();
+    for (transition, counter) in [("enable", &ENABLES), ("disable", &DISABLES)] {
+        writeln!(
+            out,
+            "io_transitions_total{{transition=\"{transition}\"}} {}",
+            counter.get()
+        )
+        .unwrap();
+    }
+
+    out
+}
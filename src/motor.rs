@@ -1,3 +1,4 @@
+use crate::metrics;
 use crate::path::Angle;
 use rppal::gpio::{Gpio, OutputPin};
 use std::thread::sleep;
@@ -73,13 +74,13 @@ impl Drivable for Car {
     fn enable(&mut self) {
         self.enabled = true;
         self.init();
-        println!("Car enabled.");
+        log::info!("Car enabled.");
     }
 
     fn disable(&mut self) {
         self.stop();
         self.enabled = false;
-        println!("Car disabled.");
+        log::info!("Car disabled.");
     }
 
     fn is_enabled(&self) -> bool {
@@ -89,12 +90,14 @@ impl Drivable for Car {
     fn drive_left(&mut self, duty_cycle: f64) {
         if self.enabled {
             self.left.set_pwm_frequency(PWM_FREQ, duty_cycle).unwrap();
+            metrics::LEFT_DUTY_CYCLE.set(duty_cycle);
         }
     }
 
     fn drive_right(&mut self, duty_cycle: f64) {
         if self.enabled {
             self.right.set_pwm_frequency(PWM_FREQ, duty_cycle).unwrap();
+            metrics::RIGHT_DUTY_CYCLE.set(duty_cycle);
         }
     }
 
@@ -1,19 +1,42 @@
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::ops::Range;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use opencv::core::{bitwise_or, in_range, Mat, Rect, VecN, Vector};
-use opencv::imgproc::{cvt_color, COLOR_BGR2HSV, COLOR_GRAY2BGR};
+use opencv::core::{bitwise_or, in_range, Mat, Rect, Scalar, Size, VecN, Vector, CV_8UC1};
+use opencv::imgproc::{
+    cvt_color, resize, COLOR_BGR2Lab, COLOR_BGR2YCrCb, COLOR_BGR2HSV, COLOR_GRAY2BGR, INTER_NEAREST,
+};
 use opencv::prelude::*;
-use opencv::videoio::{VideoCapture, VideoWriter};
-use toml::{Table, Value};
+use opencv::videoio::{VideoCapture, VideoWriter, CAP_PROP_FPS};
+use serde_json::json;
+use toml_edit::Document;
 
+use crate::adaptive::{Adapter, AdaptiveConfig};
+use crate::camera::{CameraConfig, Undistorter};
+use crate::cleanup::CleanupConfig;
+use crate::config::{self, ConfigError, BASE_PROFILE};
+use crate::foxglove;
+use crate::logging;
+use crate::metrics;
 use crate::motor::Drivable;
-use crate::remote::CarControl;
+use crate::perspective::{Perspective, PerspectiveConfig};
+use crate::record::{self, RecordConfig};
+use crate::remote::{CarControl, ConfigControl};
+use crate::snapshot::{Snapshot, SnapshotControl};
 use crate::tests::draw_ray;
 
 /// Angle between -90 (left) and 90 (right)
 pub type Angle = f64;
 
+/// Distance to the finish line to stop at, in pixels, if masks are not seen from above.
+const STOP_DIST: u32 = 10;
+
+/// Distance within which a line or obstacle may be steered towards, in pixels,
+/// if masks are not seen from above.
+const NEAR_DIST: u32 = 150;
+
 /// Returns absolute distance between two points.
 pub fn point_dist(first: &(f32, f32), second: &(f32, f32)) -> f32 {
     f32::sqrt((first.0 - second.0).powf(2.0) + (first.1 - second.1).powf(2.0))
@@ -36,261 +59,785 @@ pub struct Frame {
     finish: Mat,
     /// Size of the frame.
     size: (i32, i32),
+    /// Centimetres of ground each pixel covers, if the masks are seen from above.
+    cm_per_pixel: Option<f32>,
+    /// Distance to the finish line to stop at, in the units of dist.
+    stop_dist: u32,
+    /// Distance within which a line or obstacle may be steered towards, in the units of dist.
+    near_dist: u32,
 }
 
 impl Frame {
     pub fn reference_point(&self) -> (i32, i32) {
         (self.size.0 / 2, self.size.1)
     }
+
+    /// Returns the distance from the reference point to the point at index,
+    /// in centimetres if the masks are seen from above, otherwise in pixels.
+    pub fn dist(&self, index: &i32) -> u32 {
+        let origin = (
+            self.reference_point().0 as f32,
+            self.reference_point().1 as f32,
+        );
+        let coords = img_index_to_coord(&self.size.0, index);
+        let dist = point_dist(&origin, &(coords.0 as f32, coords.1 as f32));
+        (dist * self.cm_per_pixel.unwrap_or(1.0)) as u32
+    }
+}
+
+/// Colour space an image is thresholded in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum ColourSpace {
+    #[default]
+    Hsv,
+    Lab,
+    YCrCb,
 }
 
-/// Models the HSV thresholds for object detection.
+impl ColourSpace {
+    pub const ALL: [ColourSpace; 3] = [Self::Hsv, Self::Lab, Self::YCrCb];
+
+    /// Returns the name of the colour space in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Hsv => "hsv",
+            Self::Lab => "lab",
+            Self::YCrCb => "ycrcb",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|space| space.name() == name)
+    }
+
+    /// Returns the names and maximums of the channels, as OpenCV stores them in 8 bit images.
+    pub fn channels(&self) -> [(&'static str, u8); 3] {
+        match self {
+            Self::Hsv => [("hue", 179), ("saturation", 255), ("value", 255)],
+            Self::Lab => [("lightness", 255), ("a", 255), ("b", 255)],
+            Self::YCrCb => [
+                ("luma", 255),
+                ("red difference", 255),
+                ("blue difference", 255),
+            ],
+        }
+    }
+
+    /// Converts a BGR image to this colour space.
+    pub fn convert(&self, bgr: &Mat) -> Mat {
+        let code = match self {
+            Self::Hsv => COLOR_BGR2HSV,
+            Self::Lab => COLOR_BGR2Lab,
+            Self::YCrCb => COLOR_BGR2YCrCb,
+        };
+        let mut converted = Mat::default();
+        cvt_color(bgr, &mut converted, code, 0)
+            .unwrap_or_else(|err| panic!("Failed to convert img to {}: {err}", self.name()));
+        converted
+    }
+}
+
+/// Inclusive range of colours.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ColourRange {
+    pub lower: [u8; 3],
+    pub upper: [u8; 3],
+}
+
+/// Colours of an object class, detected where any of the ranges match.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Thresholds {
+    pub space: ColourSpace,
+    pub ranges: Vec<ColourRange>,
+}
+
+impl Thresholds {
+    /// Returns the (lower, upper) bounds to threshold with.
+    /// HSV ranges are split in two where the hue wraps around past 179,
+    /// i.e. when the lower hue is above the upper, e.g. for reds.
+    pub fn bounds(&self) -> Vec<([u8; 3], [u8; 3])> {
+        let mut bounds = vec![];
+        for range in &self.ranges {
+            let ([lower_hue, s, v], [upper_hue, max_s, max_v]) = (range.lower, range.upper);
+            if self.space == ColourSpace::Hsv && lower_hue > upper_hue {
+                bounds.push(([lower_hue, s, v], [179, max_s, max_v]));
+                bounds.push(([0, s, v], [upper_hue, max_s, max_v]));
+            } else {
+                bounds.push((range.lower, range.upper));
+            }
+        }
+        bounds
+    }
+}
+
+/// Models the colour thresholds for object detection.
+#[derive(Clone)]
 pub struct DrivableConfig {
-    pub left_lower: Vector<u8>,
-    pub left_upper: Vector<u8>,
-    pub right_lower: Vector<u8>,
-    pub right_upper: Vector<u8>,
-    pub box_lower: Vector<u8>,
-    pub box_upper: Vector<u8>,
-    pub car_lower: Vector<u8>,
-    pub car_upper: Vector<u8>,
-    pub finish_lower: Vector<u8>,
-    pub finish_upper: Vector<u8>,
+    pub left: Thresholds,
+    pub right: Thresholds,
+    pub boxes: Thresholds,
+    pub cars: Thresholds,
+    pub finish: Thresholds,
     pub p_gain: f64,
     pub i_gain: f64,
     pub i_max: f64,
     pub speed: f64,
+    /// Region of the camera frame to consider.
+    pub roi: Rect,
+    /// Profile of thresholds.toml the config was read from.
+    pub profile: String,
 }
 
 impl DrivableConfig {
-    pub fn from_toml(path: &str) -> Self {
-        let content = std::fs::read_to_string(path).unwrap();
-        let table = content.parse::<Table>().unwrap();
-        let mut vals = HashMap::new();
-        for key in ["left", "right", "box", "car", "finish"] {
-            vals.insert(key.to_owned(), Self::parse_threshold(&table, key));
-        }
-
-        let left = vals.get("left").unwrap();
-        let right = vals.get("right").unwrap();
-        let box_ = vals.get("box").unwrap();
-        let car = vals.get("car").unwrap();
-        let finish = vals.get("finish").unwrap();
-        Self {
-            left_lower: Vector::from(left.0.clone()),
-            left_upper: Vector::from(left.1.clone()),
-            right_lower: Vector::from(right.0.clone()),
-            right_upper: Vector::from(right.1.clone()),
-            box_lower: Vector::from(box_.0.clone()),
-            box_upper: Vector::from(box_.1.clone()),
-            car_lower: Vector::from(car.0.clone()),
-            car_upper: Vector::from(car.1.clone()),
-            finish_upper: Vector::from(finish.0.clone()),
-            finish_lower: Vector::from(finish.1.clone()),
-            p_gain: Self::parse_float(&table, "p_gain", Some(0.0..1.0)),
-            i_gain: Self::parse_float(&table, "i_gain", Some(0.0..1.0)),
-            i_max: Self::parse_float(&table, "i_max", None),
-            speed: Self::parse_float(&table, "speed", Some(0.0..1.0)),
-        }
-    }
-
-    fn parse_threshold(table: &Table, key: &str) -> (Vec<u8>, Vec<u8>) {
-        let lower: Vec<u8> = match &table[&format!("{key}_lower")] {
-            Value::Array(vals) => {
-                let mut _lower = vec![];
-                for val in vals {
-                    match val {
-                        Value::Integer(int) => {
-                            _lower.push(*int as u8);
-                        }
-                        _ => panic!("Members of {key}_lower must be ints."),
-                    }
-                }
-                _lower
-            }
-            _ => panic!("{key}_lower must be array."),
+    /// Reads and checks the named profile of the config at path,
+    /// or the one the config chooses if None.
+    /// Returns every problem with it if it is invalid.
+    pub fn from_toml(path: &str, profile: Option<&str>) -> Result<Self, Vec<ConfigError>> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| vec![ConfigError::new("", None, format!("Failed to read: {err}"))])?;
+        config::parse_profile(&content, profile)
+    }
+
+    /// Returns the names of the profiles in the config at path, the base first.
+    pub fn profiles(path: &str) -> Vec<String> {
+        config::profiles(&fs::read_to_string(path).unwrap_or_default())
+    }
+
+    /// Checks the roi fits inside frames of the given resolution (width, height).
+    pub fn validate_roi(roi: &Rect, resolution: (i32, i32)) -> Result<(), String> {
+        if roi.x < 0 || roi.y < 0 || roi.width <= 0 || roi.height <= 0 {
+            return Err(format!(
+                "ROI {roi:?} must have a positive size and position."
+            ));
+        }
+        if roi.x + roi.width > resolution.0 || roi.y + roi.height > resolution.1 {
+            return Err(format!(
+                "ROI {roi:?} does not fit in {}x{} frames.",
+                resolution.0, resolution.1
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the values that differ in new, as key: old -> new.
+    pub fn diff(&self, new: &Self) -> Vec<String> {
+        let (Ok(old), Ok(new)) = (
+            self.to_toml().parse::<toml::Table>(),
+            new.to_toml().parse::<toml::Table>(),
+        ) else {
+            return vec![];
         };
+        old.iter()
+            .filter_map(|(key, old_val)| {
+                let new_val = new.get(key)?;
+                (old_val != new_val).then(|| format!("{key}: {old_val} -> {new_val}"))
+            })
+            .collect()
+    }
 
-        let upper: Vec<u8> = match &table[&format!("{key}_upper")] {
-            Value::Array(vals) => {
-                let mut _upper = vec![];
-                for val in vals {
-                    match val {
-                        Value::Integer(int) => {
-                            _upper.push(*int as u8);
-                        }
-                        _ => panic!("Members of {key}_upper must be ints."),
-                    }
+    /// Writes this config to the TOML file at path.
+    /// Existing keys keep their formatting and comments,
+    /// and the previous version of the file is kept as a backup.
+    /// Profiles other than the base only get the keys that changed, so the rest is still inherited.
+    pub fn save_toml(&self, path: &str) -> io::Result<()> {
+        let (content, mut doc) = match fs::read_to_string(path) {
+            Ok(content) => {
+                Self::backup(path)?;
+                let doc = content
+                    .parse::<Document>()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                (content, doc)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (String::new(), Document::new()),
+            Err(err) => return Err(err),
+        };
+        if self.profile == BASE_PROFILE {
+            for (key, val) in self.values() {
+                Self::set_toml_value(doc.as_table_mut(), key, val);
+            }
+        } else {
+            let saved = config::parse_profile(&content, Some(&self.profile)).map_err(|errors| {
+                io::Error::new(io::ErrorKind::InvalidData, config::report(path, &errors))
+            })?;
+            let table = doc
+                .get_mut("profiles")
+                .and_then(|profiles| profiles.get_mut(&self.profile))
+                .and_then(|profile| profile.as_table_mut())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("[profiles.{}] is not a table in {path}", self.profile),
+                    )
+                })?;
+            for ((key, val), (_, saved)) in self.values().into_iter().zip(saved.values()) {
+                if val.to_string() != saved.to_string() {
+                    Self::set_toml_value(table, key, val);
                 }
-                _upper
             }
-            _ => panic!("{key}_upper must be array."),
+        }
+        Self::write_atomic(path, doc.to_string())
+    }
+
+    /// Replaces the file at path with contents, so readers never see it half written.
+    pub(crate) fn write_atomic(path: &str, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        let tmp = format!("{path}.tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Returns this config as a TOML document.
+    pub fn to_toml(&self) -> String {
+        let mut doc = Document::new();
+        for (key, val) in self.values() {
+            Self::set_toml_value(doc.as_table_mut(), key, val);
+        }
+        doc.to_string()
+    }
+
+    /// Returns the keys and values of this config.
+    fn values(&self) -> Vec<(&'static str, toml_edit::Value)> {
+        let mut values = vec![];
+        // A single range is written as a plain [hue, saturation, value] or the like.
+        let write_bounds = |bounds: Vec<[u8; 3]>| -> toml_edit::Value {
+            let array = |bound: [u8; 3]| {
+                bound
+                    .iter()
+                    .map(|val| *val as i64)
+                    .collect::<toml_edit::Array>()
+            };
+            match bounds[..] {
+                [bound] => array(bound).into(),
+                _ => bounds
+                    .into_iter()
+                    .map(array)
+                    .collect::<toml_edit::Array>()
+                    .into(),
+            }
         };
+        for (space_key, lower_key, upper_key, thresholds) in [
+            ("left_space", "left_lower", "left_upper", &self.left),
+            ("right_space", "right_lower", "right_upper", &self.right),
+            ("box_space", "box_lower", "box_upper", &self.boxes),
+            ("car_space", "car_lower", "car_upper", &self.cars),
+            ("finish_space", "finish_lower", "finish_upper", &self.finish),
+        ] {
+            let lower = thresholds.ranges.iter().map(|range| range.lower).collect();
+            let upper = thresholds.ranges.iter().map(|range| range.upper).collect();
+            values.push((space_key, thresholds.space.name().into()));
+            values.push((lower_key, write_bounds(lower)));
+            values.push((upper_key, write_bounds(upper)));
+        }
+        for (key, val) in [
+            ("p_gain", self.p_gain),
+            ("i_gain", self.i_gain),
+            ("i_max", self.i_max),
+            ("speed", self.speed),
+        ] {
+            values.push((key, val.into()));
+        }
+        let roi = [self.roi.x, self.roi.y, self.roi.width, self.roi.height]
+            .iter()
+            .map(|val| *val as i64)
+            .collect::<toml_edit::Array>();
+        values.push(("roi", roi.into()));
+        values
+    }
 
-        (lower, upper)
+    /// Replaces the value of key in table, keeping any surrounding comments.
+    pub(crate) fn set_toml_value(
+        table: &mut toml_edit::Table,
+        key: &str,
+        mut new: toml_edit::Value,
+    ) {
+        match table.get_mut(key).and_then(|item| item.as_value_mut()) {
+            Some(old) => {
+                *new.decor_mut() = old.decor().clone();
+                *old = new;
+            }
+            None => table[key] = toml_edit::Item::Value(new),
+        }
     }
 
-    fn parse_float(table: &Table, key: &str, range: Option<Range<f64>>) -> f64 {
-        match table[key] {
-            Value::Float(val) => {
-                if let Some(_range) = range {
-                    if _range.contains(&val) {
-                        return val;
-                    } else {
-                        panic!("Value {key} must be in range {_range:?}");
-                    }
+    /// Returns the directory backups of the config at path are kept in.
+    fn history_dir(path: &str) -> PathBuf {
+        PathBuf::from(format!("{path}.history"))
+    }
+
+    /// Copies the config at path into its history directory.
+    /// Returns the timestamp of the backup, in unix milliseconds.
+    /// Backups made in the same millisecond take the next free one, so none are overwritten.
+    pub(crate) fn backup(path: &str) -> io::Result<u64> {
+        let mut timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let dir = Self::history_dir(path);
+        fs::create_dir_all(&dir)?;
+        let content = fs::read(path)?;
+        loop {
+            let backup = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dir.join(format!("{timestamp}.toml")));
+            match backup {
+                Ok(mut file) => {
+                    file.write_all(&content)?;
+                    return Ok(timestamp);
                 }
-                val
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => timestamp += 1,
+                Err(err) => return Err(err),
             }
-            _ => panic!("Expected float for key {key}"),
         }
     }
+
+    /// Returns the timestamps of all backups of the config at path, oldest first.
+    pub fn history(path: &str) -> io::Result<Vec<u64>> {
+        let mut timestamps = vec![];
+        let entries = match fs::read_dir(Self::history_dir(path)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(timestamps),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let name = entry?.file_name();
+            if let Some(timestamp) = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".toml"))
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                timestamps.push(timestamp);
+            }
+        }
+        timestamps.sort();
+        Ok(timestamps)
+    }
+
+    /// Replaces the config at path with the backup from timestamp.
+    /// The current config is backed up first.
+    /// Returns the named profile of the restored config.
+    pub fn restore(path: &str, timestamp: u64, profile: &str) -> io::Result<Self> {
+        let backup = Self::history_dir(path).join(format!("{timestamp}.toml"));
+        if !backup.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No backup with timestamp {timestamp}"),
+            ));
+        }
+        let content = fs::read(backup)?;
+        Self::backup(path)?;
+        Self::write_atomic(path, content)?;
+        Self::from_toml(path, Some(profile)).map_err(|errors| {
+            io::Error::new(io::ErrorKind::InvalidData, config::report(path, &errors))
+        })
+    }
+}
+
+/// Optional stages of turning frames into masks, each from its own table of the config.
+#[derive(Default)]
+pub struct VisionConfig {
+    pub camera: Option<CameraConfig>,
+    pub adaptive: Option<AdaptiveConfig>,
+    pub cleanup: Option<CleanupConfig>,
+    pub perspective: Option<PerspectiveConfig>,
+}
+
+impl VisionConfig {
+    /// Returns the problems with every table if any are invalid.
+    pub fn from_toml(path: &str) -> Result<Self, Vec<ConfigError>> {
+        let mut errors = vec![];
+        let camera = CameraConfig::from_toml(path).unwrap_or_else(|section_errors| {
+            errors.extend(section_errors);
+            None
+        });
+        let adaptive = AdaptiveConfig::from_toml(path).unwrap_or_else(|section_errors| {
+            errors.extend(section_errors);
+            None
+        });
+        let cleanup = CleanupConfig::from_toml(path).unwrap_or_else(|section_errors| {
+            errors.extend(section_errors);
+            None
+        });
+        let perspective = PerspectiveConfig::from_toml(path).unwrap_or_else(|section_errors| {
+            errors.extend(section_errors);
+            None
+        });
+        if !errors.is_empty() {
+            errors.sort_by_key(|err: &ConfigError| err.line);
+            return Err(errors);
+        }
+        Ok(VisionConfig {
+            camera,
+            adaptive,
+            cleanup,
+            perspective,
+        })
+    }
+}
+
+/// Video of the masks and chosen angle of each frame, for debugging.
+pub struct DebugVideo {
+    writer: VideoWriter,
+    /// Size of the video's frames, fixed when it is opened.
+    size: Size,
+}
+
+impl DebugVideo {
+    /// Opens an MP4 at path with frames of the given size.
+    pub fn new(path: &str, size: Size) -> opencv::Result<Self> {
+        let writer = VideoWriter::new(
+            path,
+            VideoWriter::fourcc('m', 'p', '4', 'v')?,
+            30.0,
+            size,
+            true,
+        )?;
+        Ok(Self { writer, size })
+    }
+
+    /// Writes a frame, scaled to the size of the video if the roi has changed since it was opened.
+    fn write(&mut self, frame: &Mat) {
+        if frame.size().unwrap() == self.size {
+            self.writer.write(frame).unwrap();
+        } else {
+            let mut scaled = Mat::default();
+            resize(frame, &mut scaled, self.size, 0.0, 0.0, INTER_NEAREST).unwrap();
+            self.writer.write(&scaled).unwrap();
+        }
+    }
+
+    fn release(&mut self) {
+        self.writer.release().unwrap();
+    }
 }
 
 /// Reads a video stream and tells a car which way to turn.
 pub struct Pathfinder<T: Drivable> {
     /// Current driving angle.
     pub angle: Angle,
-    /// Region of input to consider.
-    pub roi: Rect,
     /// Car to drive.
     pub car: CarControl<T>,
     /// Thresholds to use to choose driving angle.
-    pub config: DrivableConfig,
+    pub config: ConfigControl,
     /// Debug video output.
-    debug_out: Option<VideoWriter>,
+    debug_out: Option<DebugVideo>,
+    /// Undoes lens distortion, if the camera is calibrated.
+    undistorter: Option<Undistorter>,
+    /// Requests for snapshots of the next frame.
+    snapshots: SnapshotControl,
+    /// Where to record runs, if they are recorded.
+    record: Option<RecordConfig>,
+    /// Adapts thresholds to the lighting, if they are adapted.
+    adaptive: Option<Adapter>,
+    /// How to clean each class's mask, if they are cleaned.
+    cleanup: Option<CleanupConfig>,
+    /// Warps masks to a top-down view, if they are warped.
+    perspective: Option<Perspective>,
     /// Integral for PID controller.
     angle_integral: f64,
+    /// Time the current frame was read, in nanoseconds since the Unix epoch.
+    frame_time: u64,
 }
 
 impl<T: Drivable + Send> Pathfinder<T> {
-    pub fn new(car: CarControl<T>, config: DrivableConfig, debug_out: Option<VideoWriter>) -> Self {
+    pub fn new(
+        car: CarControl<T>,
+        config: ConfigControl,
+        debug_out: Option<DebugVideo>,
+        snapshots: SnapshotControl,
+        record: Option<RecordConfig>,
+        vision: VisionConfig,
+    ) -> Self {
         Pathfinder {
             angle: 0.0,
-            roi: Rect {
-                x: 0,
-                y: 230,
-                width: 640,
-                height: 250,
-            },
             car,
             config,
             debug_out,
+            undistorter: vision.camera.as_ref().map(Undistorter::new),
+            snapshots,
+            record,
+            adaptive: vision.adaptive.map(Adapter::new),
+            cleanup: vision.cleanup,
+            perspective: vision.perspective.map(Perspective::new),
             angle_integral: 0.0,
+            frame_time: 0,
         }
     }
 
     /// Drives at angle determined by data read from cap.
     pub fn drive(&mut self, mut cap: VideoCapture) {
-        while !self.car.is_enabled() {}
         let mut bgr_img = Mat::default();
+        while !self.car.is_enabled() {
+            // Keep serving frames for the ROI editor while waiting.
+            if self.snapshots.frame_requested() && cap.read(&mut bgr_img).unwrap_or(false) {
+                self.undistort(&mut bgr_img);
+                self.snapshots.fulfil_frame(&bgr_img);
+            }
+        }
+        if let Some(record) = &self.record {
+            if let Err(err) = record::start(record) {
+                log::error!("Failed to start recording in {}: {err}", record.dir);
+            }
+        }
+        let mut last_config = String::new();
+        let fps = cap.get(CAP_PROP_FPS).unwrap_or(0.0);
         while let Ok(true) = cap.read(&mut bgr_img) {
+            self.undistort(&mut bgr_img);
+            self.frame_time = foxglove::now();
+            foxglove::publish_image(&foxglove::RAW_IMAGE, self.frame_time, &bgr_img, ".jpg");
+            if foxglove::wanted(&foxglove::CONFIG) {
+                // Published when it changes, so each run starts with the config in effect.
+                let config = self.config.get().to_toml();
+                if config != last_config {
+                    foxglove::publish(
+                        &foxglove::CONFIG,
+                        self.frame_time,
+                        &json!({"timestamp": foxglove::time(self.frame_time), "toml": config}),
+                    );
+                    last_config = config;
+                }
+            }
+            if self.snapshots.frame_requested() {
+                self.snapshots.fulfil_frame(&bgr_img);
+            }
+            let start = Instant::now();
             let angle = self.consider_frame(&bgr_img);
-            let speed = self.config.speed * 100.0;
-            println!("Angle: {angle}, Speed: {}", speed);
-            self.car.angle(angle, speed as isize);
+            let latency = start.elapsed().as_secs_f64();
+            metrics::FRAME_LATENCY.observe(latency);
+            metrics::FRAMES_PROCESSED.inc();
+            if fps > 0.0 {
+                metrics::FRAMES_DROPPED.inc_by((latency * fps) as u64);
+            }
+            let speed = self.config.get().speed * 100.0;
+            log::debug!("Angle: {angle}, Speed: {}", speed);
+            if !self.car.teleop_active() {
+                metrics::ANGLE.set(angle);
+                metrics::SPEED.set(speed);
+                self.car.angle(angle, speed as isize);
+            }
+            foxglove::publish(
+                &foxglove::ANGLE,
+                self.frame_time,
+                &json!({"timestamp": foxglove::time(self.frame_time), "angle": angle, "speed": speed}),
+            );
+            foxglove::publish(
+                &foxglove::DUTY_CYCLE,
+                self.frame_time,
+                &json!({
+                    "timestamp": foxglove::time(self.frame_time),
+                    "left": metrics::LEFT_DUTY_CYCLE.get(),
+                    "right": metrics::RIGHT_DUTY_CYCLE.get(),
+                }),
+            );
+            logging::broadcast(
+                "telemetry",
+                &json!({
+                    "angle": angle,
+                    "speed": speed,
+                    "left_duty_cycle": metrics::LEFT_DUTY_CYCLE.get(),
+                    "right_duty_cycle": metrics::RIGHT_DUTY_CYCLE.get(),
+                })
+                .to_string(),
+            );
 
             if !self.car.is_enabled() {
-                if self.debug_out.is_some() {
-                    self.debug_out.as_mut().unwrap().release().unwrap();
+                if let Some(debug_out) = self.debug_out.as_mut() {
+                    debug_out.release();
                 }
                 break;
             }
         }
+        record::finish();
     }
 
-    /// Chooses an angle to drive at from the lines in the frame.
-    /// Returns the angle most commonly suggested by the last 5 frames.
+    /// Chooses an angle to drive at from the objects in the frame with choose_angle.
+    /// Returns it after passing it through the PID controller.
     pub fn consider_frame(&mut self, bgr: &Mat) -> Angle {
-        let mut hsv = Mat::default();
-        cvt_color(&bgr, &mut hsv, COLOR_BGR2HSV, 0).expect("Failed to convert img to HSV");
-        let hsv_roi = Mat::roi(&hsv, self.roi).expect("Failed to slice region of HSV img.");
-        let frame = self.parse_frame(&hsv_roi);
+        let roi = self.config.get().roi;
+        let bgr_roi = Mat::roi(bgr, roi).expect("Failed to slice region of img.");
+        let frame = self.parse_frame(&bgr_roi);
 
-        let mut angle = self.choose_angle(&frame);
-        angle = self.pid_consider_angle(angle);
+        let chosen = self.choose_angle(&frame);
+        let angle = self.pid_consider_angle(chosen);
+        foxglove::publish(
+            &foxglove::DECISION,
+            self.frame_time,
+            &json!({
+                "timestamp": foxglove::time(self.frame_time),
+                "chosen": chosen,
+                "pid": angle,
+                "integral": self.angle_integral,
+            }),
+        );
 
         // DEBUG
-        if self.debug_out.is_some() {
+        if self.debug_out.is_some() || foxglove::wanted(&foxglove::OVERLAY_IMAGE) {
             let mut line_mask = Mat::default();
             bitwise_or(&frame.left, &frame.right, &mut line_mask, &Mat::default()).unwrap();
             let mut bgr_lines = Mat::default();
             cvt_color(&line_mask, &mut bgr_lines, COLOR_GRAY2BGR, 0).unwrap();
+            // Obstacles in blue and the finish in green, as cleaned, alongside the lines.
+            bgr_lines
+                .set_to(&Scalar::new(255.0, 0.0, 0.0, 0.0), &frame.obstacles)
+                .unwrap();
+            bgr_lines
+                .set_to(&Scalar::new(0.0, 255.0, 0.0, 0.0), &frame.finish)
+                .unwrap();
 
             draw_ray(&mut bgr_lines, &angle, VecN::new(0.0, 0.0, 255.0, 255.0));
-            self.debug_out.as_mut().unwrap().write(&bgr_lines).unwrap();
+            foxglove::publish_image(
+                &foxglove::OVERLAY_IMAGE,
+                self.frame_time,
+                &bgr_lines,
+                ".jpg",
+            );
+            if let Some(debug_out) = self.debug_out.as_mut() {
+                debug_out.write(&bgr_lines);
+            }
         }
         // DEBUG
 
+        for (channel, mask) in [
+            (&foxglove::LEFT_MASK, &frame.left),
+            (&foxglove::RIGHT_MASK, &frame.right),
+            (&foxglove::OBSTACLE_MASK, &frame.obstacles),
+            (&foxglove::FINISH_MASK, &frame.finish),
+        ] {
+            foxglove::publish_image(channel, self.frame_time, mask, ".png");
+        }
+
+        if self.snapshots.requested() {
+            self.snapshots.fulfil(Snapshot {
+                bgr: bgr.try_clone().unwrap(),
+                hsv_roi: ColourSpace::Hsv.convert(&bgr_roi),
+                masks: vec![
+                    ("left", frame.left),
+                    ("right", frame.right),
+                    ("obstacles", frame.obstacles),
+                    ("finish", frame.finish),
+                ],
+                config: self.config.get().to_toml(),
+                angle,
+            });
+        }
+
         angle
     }
 
-    /// Parses a Frame from an image matrix.
-    fn parse_frame(&self, frame: &Mat) -> Frame {
+    /// Undoes lens distortion in frame, if the camera is calibrated.
+    fn undistort(&mut self, frame: &mut Mat) {
+        if let Some(undistorter) = self.undistorter.as_mut() {
+            *frame = undistorter.undistort(frame);
+        }
+    }
+
+    /// Parses a Frame from the region of interest of a BGR image,
+    /// seen from above if there is a perspective to warp it with.
+    fn parse_frame(&mut self, bgr_roi: &Mat) -> Frame {
         // TODO change to result
-        let (mut left_mask, mut right_mask) = (Mat::default(), Mat::default());
-        in_range(
-            frame,
-            &self.config.left_lower,
-            &self.config.left_upper,
-            &mut left_mask,
-        )
-        .unwrap();
-        in_range(
-            frame,
-            &self.config.right_lower,
-            &self.config.right_upper,
-            &mut right_mask,
-        )
-        .unwrap();
+        let config = self.config.get();
+        // Converted once per colour space in use.
+        let mut converted = HashMap::new();
+        let adaptive = &mut self.adaptive;
+        let cleanup = &self.cleanup;
+        let perspective = &mut self.perspective;
+        let mut effective = vec![];
+        let mut mask = |class: &'static str, configured: &Thresholds| {
+            let thresholds = match adaptive.as_mut() {
+                Some(adapter) => adapter.thresholds(class, configured),
+                None => configured.clone(),
+            };
+            let mask = if thresholds.ranges.is_empty() {
+                threshold(bgr_roi, &[])
+            } else {
+                let image = converted
+                    .entry(thresholds.space)
+                    .or_insert_with(|| thresholds.space.convert(bgr_roi));
+                if let Some(adapter) = adaptive.as_mut() {
+                    adapter.observe(class, image);
+                }
+                threshold(image, &thresholds.bounds())
+            };
+            effective.push((class, thresholds));
+            let mask = match cleanup {
+                Some(cleanup) => cleanup.clean(class, &mask),
+                None => mask,
+            };
+            match perspective.as_mut() {
+                Some(perspective) => perspective.warp(&mask, config.roi),
+                None => mask,
+            }
+        };
+        let left_mask = mask("left", &config.left);
+        let right_mask = mask("right", &config.right);
 
-        let (mut box_mask, mut car_mask) = (Mat::default(), Mat::default());
-        in_range(
-            frame,
-            &self.config.box_lower,
-            &self.config.box_upper,
-            &mut box_mask,
-        )
-        .unwrap();
-        in_range(
-            frame,
-            &self.config.car_lower,
-            &self.config.car_upper,
-            &mut car_mask,
-        )
-        .unwrap();
+        let box_mask = mask("box", &config.boxes);
+        let car_mask = mask("car", &config.cars);
         let mut obstacle_mask = Mat::default();
         bitwise_or(&car_mask, &box_mask, &mut obstacle_mask, &Mat::default()).unwrap();
 
-        let mut finish_mask = Mat::default();
-        in_range(
-            frame,
-            &self.config.finish_lower,
-            &self.config.finish_upper,
-            &mut finish_mask,
-        )
-        .unwrap();
+        let finish_mask = mask("finish", &config.finish);
+
+        if foxglove::wanted(&foxglove::THRESHOLDS) {
+            let classes = effective
+                .iter()
+                .map(|(class, thresholds)| {
+                    let shifts = match &self.adaptive {
+                        Some(adapter) => adapter.shifts(class),
+                        None => vec![],
+                    };
+                    let ranges = thresholds
+                        .ranges
+                        .iter()
+                        .enumerate()
+                        .map(|(i, range)| {
+                            json!({
+                                "lower": range.lower,
+                                "upper": range.upper,
+                                "shift": shifts.get(i).copied().unwrap_or([0; 3]),
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    json!({"class": class, "space": thresholds.space.name(), "ranges": ranges})
+                })
+                .collect::<Vec<_>>();
+            foxglove::publish(
+                &foxglove::THRESHOLDS,
+                self.frame_time,
+                &json!({"timestamp": foxglove::time(self.frame_time), "classes": classes}),
+            );
+        }
 
         Frame {
             left: left_mask,
             right: right_mask,
             obstacles: obstacle_mask,
             finish: finish_mask,
-            size: (frame.cols(), frame.rows()),
+            size: (left_mask.cols(), left_mask.rows()),
+            cm_per_pixel: self
+                .perspective
+                .as_ref()
+                .map(|perspective| perspective.config.cm_per_pixel),
+            stop_dist: self.perspective.as_ref().map_or(STOP_DIST, |perspective| {
+                perspective.config.stop_distance as u32
+            }),
+            near_dist: self.perspective.as_ref().map_or(NEAR_DIST, |perspective| {
+                perspective.config.near_distance as u32
+            }),
         }
     }
 
     /// Considers an angle as an input to the PID controller.
     /// Returns controlled value.
     fn pid_consider_angle(&mut self, mut angle: Angle) -> Angle {
+        let config = self.config.get();
         self.angle_integral += angle;
-        if self.angle_integral > 200.0 {
-            self.angle_integral = 200.0;
-        } else if self.angle_integral < -200.0 {
-            self.angle_integral = -200.0;
+        if self.angle_integral > config.i_max {
+            self.angle_integral = config.i_max;
+        } else if self.angle_integral < -config.i_max {
+            self.angle_integral = -config.i_max;
         }
 
-        angle = (self.config.p_gain * angle) + (self.config.i_gain * self.angle_integral);
+        angle = (config.p_gain * angle) + (config.i_gain * self.angle_integral);
         if angle < -90.0 {
             angle = -90.0;
         } else if angle > 90.0 {
@@ -300,25 +847,40 @@ impl<T: Drivable + Send> Pathfinder<T> {
         angle
     }
 
-    /// Smarter choose_angle.
+    /// Casts rays out from straight ahead, turning away from each object they hit,
+    /// measured in centimetres if the frame is seen from above, otherwise in pixels.
+    /// Returns the first ray that hits nothing, or else the one whose object within near_dist
+    /// is furthest away. Stops the car if the finish line is within stop_dist.
     pub fn choose_angle(&mut self, frame: &Frame) -> Angle {
         let (mut best_angle, mut max_dist): (Angle, Option<u32>) = (0.0, None);
         let mut test_angles: VecDeque<f64> = VecDeque::from(vec![0.0]);
         let mut seen = HashSet::new();
+        let mut rays = 0;
+        let mut detections = vec![];
         while let Some(angle) = test_angles.pop_front() {
             seen.insert(angle as i64);
+            rays += 1;
             match ray_dist(frame, &angle) {
-                None => return angle,
+                None => {
+                    best_angle = angle;
+                    break;
+                }
                 Some(obj) => {
+                    metrics::detection(&obj);
+                    detections.push(json!({
+                        "angle": angle,
+                        "object": obj.name(),
+                        "distance": obj.dist(),
+                    }));
                     if let TrackObject::FinishLine(dist) = obj {
-                        if dist < 10 {
+                        if dist < frame.stop_dist {
                             self.car.disable();
                             break;
                         }
                         continue;
                     }
 
-                    if obj.dist() < 150 {
+                    if obj.dist() < frame.near_dist {
                         if let Some(dist) = max_dist {
                             if obj.dist() > dist {
                                 (best_angle, max_dist) = (angle, Some(obj.dist()));
@@ -337,6 +899,12 @@ impl<T: Drivable + Send> Pathfinder<T> {
                 }
             }
         }
+        metrics::RAYS_CAST.observe(rays as f64);
+        foxglove::publish(
+            &foxglove::DETECTIONS,
+            self.frame_time,
+            &json!({"timestamp": foxglove::time(self.frame_time), "detections": detections}),
+        );
         best_angle
     }
 }
@@ -349,6 +917,16 @@ pub enum TrackObject {
 }
 
 impl TrackObject {
+    /// Returns the kind of object in snake case.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::LeftLine(_) => "left_line",
+            Self::RightLine(_) => "right_line",
+            Self::Obstacle(_) => "obstacle",
+            Self::FinishLine(_) => "finish_line",
+        }
+    }
+
     pub fn dist(&self) -> u32 {
         match self {
             Self::LeftLine(dist)
@@ -403,6 +981,27 @@ pub fn handle_track_obj(seen: &HashSet<i64>, angle: &Angle, obj: &TrackObject) -
     angles
 }
 
+/// Returns a mask of the pixels of frame within any of the (lower, upper) bounds.
+pub(crate) fn threshold(frame: &Mat, bounds: &[([u8; 3], [u8; 3])]) -> Mat {
+    let mut mask =
+        Mat::new_rows_cols_with_default(frame.rows(), frame.cols(), CV_8UC1, Scalar::all(0.0))
+            .unwrap();
+    for (lower, upper) in bounds {
+        let mut range_mask = Mat::default();
+        in_range(
+            frame,
+            &Vector::from(lower.to_vec()),
+            &Vector::from(upper.to_vec()),
+            &mut range_mask,
+        )
+        .unwrap();
+        let mut combined = Mat::default();
+        bitwise_or(&mask, &range_mask, &mut combined, &Mat::default()).unwrap();
+        mask = combined;
+    }
+    mask
+}
+
 /// Returns all points around the center that are, at most, $dist points away.
 /// Distance can be vertical horizontal or
 fn surrounding_points(frame: &Mat, center: &i32, dist: i32) -> Vec<i32> {
@@ -429,43 +1028,20 @@ fn inspect_point(mask: &Mat, center: &i32, dist: i32, target: u8) -> bool {
 }
 
 /// Casts a ray from the bottom centre at the given angle.
-/// Returns the distance the ray travelled before hitting an obstacle.
+/// Returns the distance the ray travelled before hitting an obstacle,
+/// in centimetres if the frame is seen from above, otherwise in pixels.
 pub fn ray_dist(frame: &Frame, angle: &Angle) -> Option<TrackObject> {
     for point in cast_ray(&frame.size.0, &frame.size.1, angle) {
         let mut blocked = None;
 
         if !inspect_point(&frame.left, &point, 1, 0) {
-            let origin = (
-                frame.reference_point().0 as f32,
-                frame.reference_point().1 as f32,
-            );
-            let _coords = img_index_to_coord(&frame.size.0, &point);
-            let coords = (_coords.0 as f32, _coords.1 as f32);
-            blocked = Some(TrackObject::LeftLine(point_dist(&origin, &coords) as u32));
+            blocked = Some(TrackObject::LeftLine(frame.dist(&point)));
         } else if !inspect_point(&frame.right, &point, 1, 0) {
-            let origin = (
-                frame.reference_point().0 as f32,
-                frame.reference_point().1 as f32,
-            );
-            let _coords = img_index_to_coord(&frame.size.0, &point);
-            let coords = (_coords.0 as f32, _coords.1 as f32);
-            blocked = Some(TrackObject::RightLine(point_dist(&origin, &coords) as u32));
+            blocked = Some(TrackObject::RightLine(frame.dist(&point)));
         } else if !inspect_point(&frame.obstacles, &point, 1, 0) {
-            let origin = (
-                frame.reference_point().0 as f32,
-                frame.reference_point().1 as f32,
-            );
-            let _coords = img_index_to_coord(&frame.size.0, &point);
-            let coords = (_coords.0 as f32, _coords.1 as f32);
-            blocked = Some(TrackObject::Obstacle(point_dist(&origin, &coords) as u32));
+            blocked = Some(TrackObject::Obstacle(frame.dist(&point)));
         } else if !inspect_point(&frame.finish, &point, 1, 0) {
-            let origin = (
-                frame.reference_point().0 as f32,
-                frame.reference_point().1 as f32,
-            );
-            let _coords = img_index_to_coord(&frame.size.0, &point);
-            let coords = (_coords.0 as f32, _coords.1 as f32);
-            blocked = Some(TrackObject::FinishLine(point_dist(&origin, &coords) as u32));
+            blocked = Some(TrackObject::FinishLine(frame.dist(&point)));
         }
 
         if blocked.is_some() {
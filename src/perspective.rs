@@ -0,0 +1,195 @@
+//! Warps masks to a top-down view of the ground, see the [perspective] table of thresholds.toml.
+//!
+//! Four points on the ground are given both where they appear in the camera frame
+//! and where they are on the ground, in centimetres right of and ahead of the car.
+//! The view has the car at its bottom centre, so rays cast from there are real headings,
+//! and each of its pixels is the same distance on the ground.
+
+use opencv::core::{
+    determinant, Mat, Point2f, Rect, Scalar, Size, Vector, BORDER_CONSTANT, DECOMP_LU,
+};
+use opencv::imgproc::{get_perspective_transform, warp_perspective, INTER_NEAREST};
+use serde::Deserialize;
+
+use crate::config::{self, Checker, ConfigError, Field};
+
+/// Models the [perspective] table of the config.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PerspectiveConfig {
+    /// Four points on the ground in the camera frame, in pixels.
+    pub image: [(f32, f32); 4],
+    /// The same points on the ground, in centimetres right of and ahead of the car.
+    pub ground: [(f32, f32); 4],
+    /// Width of ground the view covers, centred on the car, in centimetres.
+    pub width: f32,
+    /// Distance ahead of the car the view covers, in centimetres.
+    pub length: f32,
+    /// Centimetres of ground each pixel of the view covers.
+    pub cm_per_pixel: f32,
+    /// Distance from the car the finish line is stopped at, in centimetres.
+    /// Ground nearer than the nearest point given is usually out of view,
+    /// so the finish is never seen much closer.
+    pub stop_distance: f32,
+    /// Distance from the car within which a line or obstacle may be steered towards,
+    /// in centimetres.
+    pub near_distance: f32,
+}
+
+/// Keys of the [perspective] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct PerspectiveSchema {
+    image: Field<[[f64; 2]; 4]>,
+    ground: Field<[[f64; 2]; 4]>,
+    width: Field<f64>,
+    length: Field<f64>,
+    cm_per_pixel: Field<f64>,
+    stop_distance: Field<f64>,
+    near_distance: Field<f64>,
+}
+
+impl PerspectiveConfig {
+    /// Returns None if there is no [perspective] table, i.e. masks are used as the camera sees them.
+    pub fn from_toml(path: &str) -> Result<Option<Self>, Vec<ConfigError>> {
+        let config = config::section(path, "perspective", |schema: PerspectiveSchema, checker| {
+            let image = points(checker, "perspective.image", schema.image);
+            let ground = points(checker, "perspective.ground", schema.ground);
+            let (Some((image, line)), Some((ground, _))) = (image, ground) else {
+                return None;
+            };
+
+            let widest = ground.iter().map(|(x, _)| x.abs()).fold(0.0, f32::max);
+            let furthest = ground.iter().map(|(_, y)| *y).fold(0.0, f32::max);
+            let nearest = ground.iter().map(|(_, y)| *y).fold(f32::INFINITY, f32::min);
+            let length = positive(checker, "perspective.length", schema.length, furthest);
+            let config = Self {
+                image,
+                ground,
+                width: positive(checker, "perspective.width", schema.width, 2.0 * widest),
+                length,
+                cm_per_pixel: positive(
+                    checker,
+                    "perspective.cm_per_pixel",
+                    schema.cm_per_pixel,
+                    1.0,
+                ),
+                stop_distance: positive(
+                    checker,
+                    "perspective.stop_distance",
+                    schema.stop_distance,
+                    nearest + 10.0,
+                ),
+                near_distance: positive(
+                    checker,
+                    "perspective.near_distance",
+                    schema.near_distance,
+                    length,
+                ),
+            };
+            if determinant(&config.homography(Rect::default())).unwrap_or(0.0) == 0.0 {
+                checker.error(
+                    "perspective.image",
+                    Some(line),
+                    "must not have 3 points in a line, nor may perspective.ground",
+                );
+            }
+            Some(config)
+        })?;
+        Ok(config.flatten())
+    }
+
+    /// Returns the size of the view in pixels.
+    pub fn size(&self) -> Size {
+        Size::new(
+            (self.width / self.cm_per_pixel).round() as i32,
+            (self.length / self.cm_per_pixel).round() as i32,
+        )
+    }
+
+    /// Returns where a point on the ground is in the view.
+    pub fn view_point(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        (
+            (x + self.width / 2.0) / self.cm_per_pixel,
+            (self.length - y) / self.cm_per_pixel,
+        )
+    }
+
+    /// Returns the homography from a region of interest of the camera frame to the view.
+    pub fn homography(&self, roi: Rect) -> Mat {
+        let image = self
+            .image
+            .iter()
+            .map(|(x, y)| Point2f::new(x - roi.x as f32, y - roi.y as f32))
+            .collect::<Vector<Point2f>>();
+        let view = self
+            .ground
+            .iter()
+            .map(|point| {
+                let (x, y) = self.view_point(*point);
+                Point2f::new(x, y)
+            })
+            .collect::<Vector<Point2f>>();
+        get_perspective_transform(&image, &view, DECOMP_LU).unwrap()
+    }
+}
+
+/// Returns the 4 [x, y] points of field, reporting them if they are missing.
+fn points(
+    checker: &mut Checker,
+    key: &str,
+    field: Field<[[f64; 2]; 4]>,
+) -> Option<([(f32, f32); 4], usize)> {
+    if let Field::Missing = field {
+        checker.error(key, None, "must be 4 [x, y] points");
+    }
+    let (points, line) = checker.get(key, field)?;
+    Some((points.map(|[x, y]| (x as f32, y as f32)), line))
+}
+
+/// Returns the value of field if it is above 0, otherwise default.
+fn positive(checker: &mut Checker, key: &str, field: Field<f64>, default: f32) -> f32 {
+    match checker.get(key, field) {
+        Some((val, _)) if val > 0.0 => val as f32,
+        Some((val, line)) => {
+            checker.error(key, Some(line), format!("must be above 0, got {val}"));
+            default
+        }
+        None => default,
+    }
+}
+
+/// Warps masks to the view, keeping the homography for the region of interest they are from.
+pub struct Perspective {
+    pub config: PerspectiveConfig,
+    /// Region of interest and its homography.
+    homography: Option<(Rect, Mat)>,
+}
+
+impl Perspective {
+    pub fn new(config: PerspectiveConfig) -> Self {
+        Perspective {
+            config,
+            homography: None,
+        }
+    }
+
+    /// Returns mask of roi seen from above.
+    pub fn warp(&mut self, mask: &Mat, roi: Rect) -> Mat {
+        if self.homography.as_ref().map(|(cached, _)| *cached) != Some(roi) {
+            self.homography = Some((roi, self.config.homography(roi)));
+        }
+        let (_, homography) = self.homography.as_ref().unwrap();
+        let mut warped = Mat::default();
+        warp_perspective(
+            mask,
+            &mut warped,
+            homography,
+            self.config.size(),
+            INTER_NEAREST,
+            BORDER_CONSTANT,
+            Scalar::all(0.0),
+        )
+        .unwrap();
+        warped
+    }
+}
@@ -0,0 +1,210 @@
+//! Records runs to MCAP files for replay in Foxglove Studio or our own tools.
+//! See https://mcap.dev/spec for the format.
+//!
+//! Everything published on a Foxglove channel while recording is written,
+//! with the same JSON encoding and timestamps.
+//! Files are unchunked and have no summary section; readers scan them instead.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::config::{self, ConfigError, Field};
+use crate::foxglove::{Channel, CHANNELS};
+
+const MAGIC: &[u8; 8] = b"\x89MCAP0\r\n";
+const OP_HEADER: u8 = 0x01;
+const OP_FOOTER: u8 = 0x02;
+const OP_SCHEMA: u8 = 0x03;
+const OP_CHANNEL: u8 = 0x04;
+const OP_MESSAGE: u8 = 0x05;
+const OP_DATA_END: u8 = 0x0F;
+
+/// Run being recorded, if any.
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+/// Models the [record] table of the config.
+pub struct RecordConfig {
+    /// Directory to write recordings to.
+    pub dir: String,
+}
+
+/// Keys of the [record] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct RecordSchema {
+    dir: Field<String>,
+}
+
+impl RecordConfig {
+    /// Returns None if there is no [record] table, i.e. runs are not recorded.
+    pub fn from_toml(path: &str) -> Result<Option<Self>, Vec<ConfigError>> {
+        config::section(path, "record", |schema: RecordSchema, checker| Self {
+            dir: checker
+                .get("record.dir", schema.dir)
+                .map_or("runs".to_string(), |(dir, _)| dir),
+        })
+    }
+}
+
+/// Content of a record, in MCAP's little endian encoding.
+#[derive(Default)]
+struct Fields(Vec<u8>);
+
+impl Fields {
+    fn u16(mut self, val: u16) -> Self {
+        self.0.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, val: u32) -> Self {
+        self.0.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, val: u64) -> Self {
+        self.0.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    /// Writes val prefixed with its u32 length.
+    fn bytes(self, val: &[u8]) -> Self {
+        let mut fields = self.u32(val.len() as u32);
+        fields.0.extend_from_slice(val);
+        fields
+    }
+
+    fn str(self, val: &str) -> Self {
+        self.bytes(val.as_bytes())
+    }
+
+    /// Writes val without a length, for fields that run to the end of the record.
+    fn rest(mut self, val: &[u8]) -> Self {
+        self.0.extend_from_slice(val);
+        self
+    }
+}
+
+struct Recorder {
+    out: BufWriter<File>,
+    path: PathBuf,
+    /// Sequence number of the last message on each channel, by channel id.
+    sequences: HashMap<u32, u32>,
+}
+
+impl Recorder {
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut recorder = Self {
+            out: BufWriter::new(File::create(path)?),
+            path: path.to_owned(),
+            sequences: HashMap::new(),
+        };
+        recorder.out.write_all(MAGIC)?;
+        recorder.write(OP_HEADER, Fields::default().str("").str("immovable-object"))?;
+        for channel in CHANNELS {
+            recorder.write(
+                OP_SCHEMA,
+                Fields::default()
+                    .u16(channel.id as u16)
+                    .str(channel.schema_name)
+                    .str("jsonschema")
+                    .bytes(channel.schema.as_bytes()),
+            )?;
+            recorder.write(
+                OP_CHANNEL,
+                Fields::default()
+                    .u16(channel.id as u16)
+                    .u16(channel.id as u16)
+                    .str(channel.topic)
+                    .str("json")
+                    // No metadata.
+                    .u32(0),
+            )?;
+        }
+        Ok(recorder)
+    }
+
+    fn write(&mut self, op: u8, fields: Fields) -> io::Result<()> {
+        self.out.write_all(&[op])?;
+        self.out.write_all(&(fields.0.len() as u64).to_le_bytes())?;
+        self.out.write_all(&fields.0)
+    }
+
+    fn message(&mut self, channel: &Channel, timestamp: u64, payload: &[u8]) -> io::Result<()> {
+        let sequence = self.sequences.entry(channel.id).or_default();
+        *sequence += 1;
+        let sequence = *sequence;
+        self.write(
+            OP_MESSAGE,
+            Fields::default()
+                .u16(channel.id as u16)
+                .u32(sequence)
+                .u64(timestamp)
+                .u64(timestamp)
+                .rest(payload),
+        )
+    }
+
+    fn finish(mut self) -> io::Result<PathBuf> {
+        // CRCs of 0 mean they were not calculated.
+        self.write(OP_DATA_END, Fields::default().u32(0))?;
+        self.write(OP_FOOTER, Fields::default().u64(0).u64(0).u32(0))?;
+        self.out.write_all(MAGIC)?;
+        self.out.flush()?;
+        Ok(self.path)
+    }
+}
+
+/// Starts recording to a new file in config.dir, named by the time.
+/// Any recording in progress is finished first.
+pub fn start(config: &RecordConfig) -> io::Result<PathBuf> {
+    finish();
+    fs::create_dir_all(&config.dir)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let path = Path::new(&config.dir).join(format!("{timestamp}.mcap"));
+    let recorder = Recorder::create(&path)?;
+    *RECORDER.lock().unwrap() = Some(recorder);
+    log::info!("Recording to {}", path.display());
+    Ok(path)
+}
+
+/// Returns true if a run is being recorded.
+pub fn recording() -> bool {
+    RECORDER.lock().unwrap().is_some()
+}
+
+/// Writes a message to the recording, if there is one.
+/// Recording stops if the file cannot be written.
+pub fn record(channel: &Channel, timestamp: u64, payload: &[u8]) {
+    let mut recorder = RECORDER.lock().unwrap();
+    if let Some(current) = recorder.as_mut() {
+        if let Err(err) = current.message(channel, timestamp, payload) {
+            log::error!("Failed to record {}, stopping: {err}", channel.topic);
+            *recorder = None;
+        }
+    }
+}
+
+/// Finishes the recording, if there is one.
+/// Returns the path of the finished file.
+pub fn finish() -> Option<PathBuf> {
+    let recorder = RECORDER.lock().unwrap().take()?;
+    match recorder.finish() {
+        Ok(path) => {
+            log::info!("Finished recording {}", path.display());
+            Some(path)
+        }
+        Err(err) => {
+            log::error!("Failed to finish recording: {err}");
+            None
+        }
+    }
+}
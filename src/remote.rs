@@ -1,20 +1,88 @@
-use crate::motor::Drivable;
+use crate::assets::{self, AssetPath};
+use crate::auth::{AuthConfig, AuthMiddleware};
+use crate::config::{self, ConfigError, Field};
+use crate::lease::{self, LeaseControl, LeaseMiddleware};
+use crate::logging;
+use crate::metrics;
+use crate::motor::{Drivable, Percent};
+use crate::path::{Angle, DrivableConfig};
+use crate::snapshot::{self, SnapshotControl};
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::{CACHE_CONTROL, CONTENT_TYPE, COOKIE};
+use gotham::hyper::{Body, HeaderMap, Response, StatusCode};
 use gotham::middleware::state::StateMiddleware;
-use gotham::pipeline::{single_middleware, single_pipeline};
+use gotham::mime;
+use gotham::pipeline::{new_pipeline, single_pipeline};
 use gotham::prelude::*;
-use gotham::router::build_router;
+use gotham::router::{build_router, Router};
+use gotham::rustls;
 use gotham::state::State;
+use inotify::{Inotify, WatchMask};
+use opencv::core::Rect;
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Returns the value of the named cookie sent with a request.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, val)| val)
+}
+
+/// Time to wait after the config file changes before reloading it.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Heartbeat timeout used if [heartbeat] doesn't set one.
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Time teleop keeps control after the last teleop command.
+const TELEOP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Reason the car was disabled without being told to stop.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fault {
+    /// The controlling client stopped sending heartbeats.
+    HeartbeatLost,
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HeartbeatLost => write!(f, "heartbeat lost"),
+        }
+    }
+}
 
 #[derive(StateData)]
 pub struct CarControl<T: Drivable> {
     inner: Arc<Mutex<T>>,
+    /// Time the last heartbeat was received.
+    heartbeat: Arc<Mutex<Instant>>,
+    /// Reason the car was last disabled, if it was a fault.
+    fault: Arc<Mutex<Option<Fault>>>,
+    /// Time the last teleop command was received.
+    teleop: Arc<Mutex<Option<Instant>>>,
 }
 
 impl<T: Drivable> Clone for CarControl<T> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            heartbeat: self.heartbeat.clone(),
+            fault: self.fault.clone(),
+            teleop: self.teleop.clone(),
         }
     }
 }
@@ -23,21 +91,89 @@ impl<T: Drivable> CarControl<T> {
     pub fn new(car: T) -> Self {
         CarControl {
             inner: Arc::new(Mutex::new(car)),
+            heartbeat: Arc::new(Mutex::new(Instant::now())),
+            fault: Arc::new(Mutex::new(None)),
+            teleop: Arc::new(Mutex::new(None)),
         }
     }
 
     fn inner(&self) -> MutexGuard<T> {
         self.inner.lock().unwrap()
     }
+
+    /// Records a heartbeat from the controlling client.
+    pub fn beat(&self) {
+        *self.heartbeat.lock().unwrap() = Instant::now();
+    }
+
+    /// Returns the reason the car was disabled, if it was a fault.
+    pub fn fault(&self) -> Option<Fault> {
+        *self.fault.lock().unwrap()
+    }
+
+    /// Disables the car and records why.
+    pub fn fail(&mut self, fault: Fault) {
+        log::error!("Fault: {fault}, disabling...");
+        *self.fault.lock().unwrap() = Some(fault);
+        self.disable();
+        logging::state_transition(&format!("fault: {fault}"));
+    }
+
+    /// Drives at the given angle and speed on behalf of a remote driver.
+    /// The pathfinder stops driving until teleop commands stop arriving.
+    pub fn teleop(&mut self, angle: Angle, speed: Percent) {
+        *self.teleop.lock().unwrap() = Some(Instant::now());
+        metrics::ANGLE.set(angle);
+        metrics::SPEED.set(speed as f64);
+        self.angle(angle, speed);
+    }
+
+    /// Returns true if a remote driver has sent teleop commands recently.
+    pub fn teleop_active(&self) -> bool {
+        self.teleop
+            .lock()
+            .unwrap()
+            .is_some_and(|last| last.elapsed() < TELEOP_TIMEOUT)
+    }
+
+    /// Disables the car whenever it is enabled and no heartbeat
+    /// has been received for longer than timeout.
+    pub fn watch_heartbeat(&self, timeout: Duration) {
+        let mut car = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(timeout / 4);
+            car.check_heartbeat(timeout, Instant::now());
+        });
+    }
+
+    /// Disables the car if it is enabled and, as of now,
+    /// no heartbeat has been received for longer than timeout.
+    pub fn check_heartbeat(&mut self, timeout: Duration, now: Instant) {
+        // Enabling can take a while, so wait for it before reading the heartbeat it records.
+        let enabled = self.inner().is_enabled();
+        let last = *self.heartbeat.lock().unwrap();
+        if enabled && now.saturating_duration_since(last) > timeout {
+            self.fail(Fault::HeartbeatLost);
+        }
+    }
 }
 
 impl<T: Drivable> Drivable for CarControl<T> {
     fn enable(&mut self) {
-        self.inner().enable();
+        *self.fault.lock().unwrap() = None;
+        let mut inner = self.inner();
+        inner.enable();
+        // The client couldn't beat while the car was starting, so its timer starts now.
+        self.beat();
+        drop(inner);
+        metrics::ENABLES.inc();
+        logging::state_transition("enabled");
     }
 
     fn disable(&mut self) {
         self.inner().disable();
+        metrics::DISABLES.inc();
+        logging::state_transition("disabled");
     }
 
     fn is_enabled(&self) -> bool {
@@ -69,46 +205,575 @@ impl<T: Drivable> Drivable for CarControl<T> {
     }
 }
 
+/// Config shared between the pathfinder and the remote.
+#[derive(Clone, StateData)]
+pub struct ConfigControl {
+    inner: Arc<Mutex<DrivableConfig>>,
+    /// Path of the TOML file the config was loaded from.
+    path: Arc<String>,
+    /// Resolution (width, height) of the camera frames.
+    resolution: Arc<Mutex<Option<(i32, i32)>>>,
+}
+
+impl ConfigControl {
+    /// Reads the named profile of the config at path, or the one the config chooses if None.
+    pub fn from_toml(path: &str, profile: Option<&str>) -> Result<Self, Vec<ConfigError>> {
+        Ok(ConfigControl {
+            inner: Arc::new(Mutex::new(DrivableConfig::from_toml(path, profile)?)),
+            path: Arc::new(path.to_owned()),
+            resolution: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Sets the resolution of the camera frames and checks the roi fits in them.
+    /// Returns the problem with the key that sets the roi if it doesn't.
+    pub fn set_resolution(&self, resolution: (i32, i32)) -> Result<(), Vec<ConfigError>> {
+        *self.resolution.lock().unwrap() = Some(resolution);
+        let current = self.get();
+        DrivableConfig::validate_roi(&current.roi, resolution).map_err(|msg| {
+            let content = fs::read_to_string(self.path.as_str()).unwrap_or_default();
+            let (key, line) = match config::setting(&content, &current.profile, "roi") {
+                Some((key, line)) => (key, Some(line)),
+                None => ("roi".to_owned(), None),
+            };
+            vec![ConfigError::new(&key, line, msg)]
+        })
+    }
+
+    /// Returns the resolution of the camera frames, if known.
+    pub fn resolution(&self) -> Option<(i32, i32)> {
+        *self.resolution.lock().unwrap()
+    }
+
+    /// Changes the region of the camera frames to consider.
+    pub fn set_roi(&self, roi: Rect) -> Result<(), String> {
+        if let Some(resolution) = self.resolution() {
+            DrivableConfig::validate_roi(&roi, resolution)?;
+        }
+        self.get().roi = roi;
+        Ok(())
+    }
+
+    /// Returns the config currently in effect.
+    pub fn get(&self) -> MutexGuard<DrivableConfig> {
+        self.inner.lock().unwrap()
+    }
+
+    /// Writes the config in effect back to its TOML file.
+    pub fn save(&self) -> std::io::Result<()> {
+        self.get().save_toml(&self.path)
+    }
+
+    /// Returns the timestamps of previous versions of the TOML file.
+    pub fn history(&self) -> std::io::Result<Vec<u64>> {
+        DrivableConfig::history(&self.path)
+    }
+
+    /// Restores the TOML file from a previous version and puts it into effect.
+    pub fn restore(&self, timestamp: u64) -> std::io::Result<()> {
+        let profile = self.get().profile.clone();
+        let config = DrivableConfig::restore(&self.path, timestamp, &profile)?;
+        if let Some(resolution) = self.resolution() {
+            DrivableConfig::validate_roi(&config.roi, resolution)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        }
+        *self.get() = config;
+        Ok(())
+    }
+
+    /// Returns the names of the profiles in the TOML file, the base first.
+    pub fn profiles(&self) -> Vec<String> {
+        DrivableConfig::profiles(&self.path)
+    }
+
+    /// Rereads the profile in effect from the TOML file and puts it into effect if it is valid.
+    /// Returns the changes made, as key: old -> new.
+    pub fn reload(&self) -> Result<Vec<String>, String> {
+        let profile = self.get().profile.clone();
+        self.select(&profile)
+    }
+
+    /// Reads the named profile from the TOML file and puts it into effect if it is valid.
+    /// Returns the changes made, as key: old -> new.
+    pub fn select(&self, profile: &str) -> Result<Vec<String>, String> {
+        let config = DrivableConfig::from_toml(&self.path, Some(profile))
+            .map_err(|errors| config::report(&self.path, &errors))?;
+        if let Some(resolution) = self.resolution() {
+            DrivableConfig::validate_roi(&config.roi, resolution)?;
+        }
+        let mut current = self.get();
+        let changes = current.diff(&config);
+        *current = config;
+        Ok(changes)
+    }
+
+    /// Reloads the config whenever its TOML file is written, on a background thread.
+    pub fn watch(&self) -> std::io::Result<()> {
+        let path = Path::new(self.path.as_str());
+        let name = path.file_name().unwrap_or_default().to_owned();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        let mut inotify = Inotify::init()?;
+        // Watch the directory as editors often replace the file rather than write to it.
+        inotify.watches().add(
+            &dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+        )?;
+        log::info!("Watching {} for changes", self.path);
+
+        let config = self.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                let changed = match inotify.read_events_blocking(&mut buf) {
+                    Ok(events) => events
+                        .into_iter()
+                        .any(|event| event.name == Some(name.as_os_str())),
+                    Err(err) => {
+                        log::error!("Stopped watching {}: {err}", config.path);
+                        return;
+                    }
+                };
+                if !changed {
+                    continue;
+                }
+                // Let the writer finish, then skip the events it caused meanwhile.
+                thread::sleep(RELOAD_DEBOUNCE);
+                while inotify
+                    .read_events(&mut buf)
+                    .is_ok_and(|events| events.count() > 0)
+                {}
+
+                match config.reload() {
+                    Ok(changes) if changes.is_empty() => {}
+                    Ok(changes) => {
+                        log::info!("Reloaded {}: {}", config.path, changes.join(", "))
+                    }
+                    Err(err) => log::error!("Not reloading {}:\n{err}", config.path),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct BackupPath {
+    timestamp: u64,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct ProfilePath {
+    name: String,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct RoiQuery {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
 pub fn enable<T: Drivable>(mut state: State) -> (State, String) {
-    println!("Enabling...");
+    log::info!("Enabling...");
     CarControl::<T>::borrow_mut_from(&mut state).enable();
     (state, "Started".to_string())
 }
 
 pub fn disable<T: Drivable>(mut state: State) -> (State, String) {
-    println!("Disabling...");
+    log::info!("Disabling...");
     let car = CarControl::<T>::borrow_mut_from(&mut state);
     car.disable();
 
     (state, "Stopped".to_string())
 }
 
-pub fn serve<T: Drivable>(car: CarControl<T>) {
-    let landing_page = tempfile::Builder::new().suffix(".html").tempfile().unwrap();
-    std::fs::write(landing_page.path(), LANDING_PAGE_HTML).unwrap();
+pub fn serve_metrics(state: State) -> (State, Response<Body>) {
+    let res = create_response(
+        &state,
+        StatusCode::OK,
+        "text/plain; version=0.0.4".parse::<mime::Mime>().unwrap(),
+        metrics::render(),
+    );
+    (state, res)
+}
+
+pub fn events(state: State) -> (State, Response<Body>) {
+    let res = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(logging::subscribe())
+        .unwrap();
+    (state, res)
+}
 
-    let (chain, pipelines) = single_pipeline(single_middleware(StateMiddleware::new(car)));
-    let router = build_router(chain, pipelines, |route| {
-        route.get("/").to_file(landing_page.path());
-        route.post("/start").to(enable::<T>);
-        route.post("/stop").to(disable::<T>);
+pub fn heartbeat<T: Drivable>(state: State) -> (State, String) {
+    CarControl::<T>::borrow_from(&state).beat();
+    (state, "Ok".to_string())
+}
+
+pub fn status<T: Drivable>(state: State) -> (State, String) {
+    let car = CarControl::<T>::borrow_from(&state);
+    let status = match (car.is_enabled(), car.fault()) {
+        (true, _) => "enabled".to_string(),
+        (false, Some(fault)) => format!("fault: {fault}"),
+        (false, None) => "disabled".to_string(),
+    };
+    (state, status)
+}
+
+/// Keys of the [heartbeat] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct HeartbeatSchema {
+    timeout_ms: Field<i64>,
+}
+
+/// Reads the heartbeat timeout from the [heartbeat] table of the config.
+/// Returns None if heartbeats are not required.
+pub fn heartbeat_timeout(path: &str) -> Result<Option<Duration>, Vec<ConfigError>> {
+    config::section(path, "heartbeat", |schema: HeartbeatSchema, checker| {
+        let default = DEFAULT_HEARTBEAT_TIMEOUT.as_millis() as i64;
+        let ms = checker.int(
+            "heartbeat.timeout_ms",
+            schema.timeout_ms,
+            default,
+            1..=i64::MAX,
+        );
+        Duration::from_millis(ms as u64)
+    })
+}
+
+pub fn get_roi(state: State) -> (State, Response<Body>) {
+    let config = ConfigControl::borrow_from(&state);
+    let roi = config.get().roi;
+    let (width, height) = config.resolution().unwrap_or((0, 0));
+    let body = json!({
+        "x": roi.x,
+        "y": roi.y,
+        "width": roi.width,
+        "height": roi.height,
+        "frame_width": width,
+        "frame_height": height,
     });
+    let res = create_response(
+        &state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        body.to_string(),
+    );
+    (state, res)
+}
+
+pub fn set_roi(mut state: State) -> (State, Response<Body>) {
+    let query = RoiQuery::take_from(&mut state);
+    let roi = Rect::new(query.x, query.y, query.width, query.height);
+    let res = match ConfigControl::borrow_from(&state).set_roi(roi) {
+        Ok(()) => {
+            log::info!("ROI set to {roi:?}");
+            create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, "Set")
+        }
+        Err(err) => create_response(&state, StatusCode::BAD_REQUEST, mime::TEXT_PLAIN, err),
+    };
+    (state, res)
+}
+
+pub fn get_config(state: State) -> (State, Response<Body>) {
+    let toml = ConfigControl::borrow_from(&state).get().to_toml();
+    let res = create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, toml);
+    (state, res)
+}
+
+pub fn save_config(state: State) -> (State, Response<Body>) {
+    let res = match ConfigControl::borrow_from(&state).save() {
+        Ok(()) => create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, "Saved"),
+        Err(err) => create_response(
+            &state,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            mime::TEXT_PLAIN,
+            format!("Failed to save config: {err}"),
+        ),
+    };
+    (state, res)
+}
+
+pub fn list_profiles(state: State) -> (State, Response<Body>) {
+    let config = ConfigControl::borrow_from(&state);
+    let body = json!({
+        "active": config.get().profile,
+        "profiles": config.profiles(),
+    });
+    let res = create_response(
+        &state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        body.to_string(),
+    );
+    (state, res)
+}
+
+pub fn select_profile(state: State) -> (State, Response<Body>) {
+    let name = &ProfilePath::borrow_from(&state).name;
+    let res = match ConfigControl::borrow_from(&state).select(name) {
+        Ok(changes) => {
+            if changes.is_empty() {
+                log::info!("Switched to profile {name}");
+            } else {
+                log::info!("Switched to profile {name}: {}", changes.join(", "));
+            }
+            create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, "Switched")
+        }
+        Err(err) => create_response(&state, StatusCode::BAD_REQUEST, mime::TEXT_PLAIN, err),
+    };
+    (state, res)
+}
+
+pub fn config_history(state: State) -> (State, Response<Body>) {
+    let res = match ConfigControl::borrow_from(&state).history() {
+        Ok(timestamps) => create_response(
+            &state,
+            StatusCode::OK,
+            mime::TEXT_PLAIN,
+            timestamps
+                .iter()
+                .map(|timestamp| timestamp.to_string())
+                .collect::<Vec<String>>()
+                .join("\n"),
+        ),
+        Err(err) => create_response(
+            &state,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            mime::TEXT_PLAIN,
+            format!("Failed to list config history: {err}"),
+        ),
+    };
+    (state, res)
+}
+
+pub fn restore_config(state: State) -> (State, Response<Body>) {
+    let timestamp = BackupPath::borrow_from(&state).timestamp;
+    log::info!("Restoring config from {timestamp}...");
+    let res = match ConfigControl::borrow_from(&state).restore(timestamp) {
+        Ok(()) => create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, "Restored"),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => create_response(
+            &state,
+            StatusCode::NOT_FOUND,
+            mime::TEXT_PLAIN,
+            err.to_string(),
+        ),
+        Err(err) => create_response(
+            &state,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            mime::TEXT_PLAIN,
+            format!("Failed to restore config: {err}"),
+        ),
+    };
+    (state, res)
+}
+
+/// Models the [remote] table of the config.
+pub struct RemoteConfig {
+    /// Address to listen on.
+    pub address: String,
+    /// Port to listen on.
+    pub port: u16,
+    /// PEM certificate chain to serve HTTPS with.
+    pub tls_cert: Option<String>,
+    /// PEM private key for tls_cert.
+    pub tls_key: Option<String>,
+}
+
+/// Keys of the [remote] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct RemoteSchema {
+    address: Field<String>,
+    port: Field<i64>,
+    tls_cert: Field<String>,
+    tls_key: Field<String>,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0".to_string(),
+            port: 80,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
 
-    gotham::start("0.0.0.0:80", router).unwrap();
-}
-
-const LANDING_PAGE_HTML: &str = "<!DOCTYPE html><html>
-<head>
-    <h1>Immovable Object Controls</h1>
-</head>
-<body>
-	<div style=\"display:flex\">
-        <form action=\"/start\" method=\"post\" target=\"dummy\">
-            <button name=\"start\" value=\"start\" style=\"padding:20px;margin:50px\">start</button>
-        </form>
-        <form action=\"/stop\" method=\"post\" target=\"dummy\">
-            <button name=\"stop\" value=\"stop\" style=\"padding:20px;margin:50px\">stop</button>
-        </form>
-        <iframe name=\"dummy\" id=\"dummy\" hidden/>
-	</div>
-</body></html>";
+impl RemoteConfig {
+    /// Returns the defaults if there is no [remote] table.
+    pub fn from_toml(path: &str) -> Result<Self, Vec<ConfigError>> {
+        let config = config::section(path, "remote", |schema: RemoteSchema, checker| {
+            let default = Self::default();
+            Self {
+                address: checker
+                    .get("remote.address", schema.address)
+                    .map_or(default.address, |(address, _)| address),
+                port: checker.int(
+                    "remote.port",
+                    schema.port,
+                    default.port as i64,
+                    0..=u16::MAX as i64,
+                ) as u16,
+                tls_cert: checker
+                    .get("remote.tls_cert", schema.tls_cert)
+                    .map(|(path, _)| path),
+                tls_key: checker
+                    .get("remote.tls_key", schema.tls_key)
+                    .map(|(path, _)| path),
+            }
+        })?;
+        Ok(config.unwrap_or_default())
+    }
+
+    /// Overrides values with those given on the command line
+    /// as --address, --port, --tls-cert and --tls-key.
+    /// Returns what is wrong with them if any are invalid.
+    pub fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut val = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("Expected a value after {arg}"))
+            };
+            match arg.as_str() {
+                "--address" => self.address = val()?,
+                "--port" => {
+                    self.port = val()?
+                        .parse()
+                        .map_err(|_| "--port must be between 0 and 65535.".to_string())?
+                }
+                "--tls-cert" => self.tls_cert = Some(val()?),
+                "--tls-key" => self.tls_key = Some(val()?),
+                _ => return Err(format!("Unknown argument {arg}")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the TLS certificate and key, if configured.
+    fn tls(&self) -> Result<Option<rustls::ServerConfig>, String> {
+        let (cert_path, key_path) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => return Err("Both tls_cert and tls_key must be set to use TLS.".to_string()),
+        };
+
+        let cert_file = File::open(cert_path)
+            .map_err(|err| format!("Failed to open TLS certificate {cert_path}: {err}"))?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+            .map_err(|err| format!("Failed to read TLS certificate {cert_path}: {err}"))?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+
+        let key_file = File::open(key_path)
+            .map_err(|err| format!("Failed to open TLS key {key_path}: {err}"))?;
+        let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(key_file))
+            .map_err(|err| format!("Failed to read TLS key {key_path}: {err}"))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("No PKCS#8 private key found in {key_path}"))?;
+
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, rustls::PrivateKey(key))
+            .map(Some)
+            .map_err(|err| format!("Invalid TLS certificate or key: {err}"))
+    }
+}
+
+/// Serves the remote until it fails.
+/// Returns an error if the server could not be started.
+pub fn serve<T: Drivable>(
+    car: CarControl<T>,
+    config: ConfigControl,
+    auth: AuthConfig,
+    snapshots: SnapshotControl,
+    remote: RemoteConfig,
+    lease: LeaseControl,
+) -> Result<(), String> {
+    let addr = format!("{}:{}", remote.address, remote.port);
+    let tls = remote.tls()?;
+    let router = router(car, config, auth, snapshots, lease);
+    match tls {
+        Some(tls) => {
+            log::info!("Serving remote on https://{addr}");
+            gotham::tls::start(addr.clone(), router, tls)
+        }
+        None => {
+            log::info!("Serving remote on http://{addr}");
+            gotham::start(addr.clone(), router)
+        }
+    }
+    // Serving only ends if the address couldn't be bound.
+    .map_err(|err| format!("Failed to bind to {addr}: {err}"))
+}
+
+/// Returns the routes of the remote, behind its auth and lease checks.
+pub(crate) fn router<T: Drivable>(
+    car: CarControl<T>,
+    config: ConfigControl,
+    auth: AuthConfig,
+    snapshots: SnapshotControl,
+    lease: LeaseControl,
+) -> Router {
+    let (chain, pipelines) = single_pipeline(
+        new_pipeline()
+            .add(AuthMiddleware::new(auth))
+            .add(LeaseMiddleware::new(lease.clone()))
+            .add(StateMiddleware::new(car))
+            .add(StateMiddleware::new(config))
+            .add(StateMiddleware::new(lease))
+            .add(StateMiddleware::new(snapshots))
+            .build(),
+    );
+    build_router(chain, pipelines, |route| {
+        route.get("/").to(assets::index);
+        route.get("/login").to(assets::login);
+        route
+            .get("/assets/:name")
+            .with_path_extractor::<AssetPath>()
+            .to(assets::asset);
+        route.post("/start").to(enable::<T>);
+        route.post("/stop").to(disable::<T>);
+        route.get("/api/status").to(status::<T>);
+        route.get("/metrics").to(serve_metrics);
+        route.get("/api/events").to(events);
+        route
+            .get("/api/snapshot")
+            .to_async(snapshot::serve_snapshot);
+        route.get("/api/frame.jpg").to_async(snapshot::serve_frame);
+        route.get("/api/roi").to(get_roi);
+        route
+            .post("/api/roi")
+            .with_query_string_extractor::<RoiQuery>()
+            .to(set_roi);
+        route.post("/api/heartbeat").to(heartbeat::<T>);
+        route.get("/api/lease").to(lease::lease_status);
+        route.post("/api/lease/takeover").to(lease::take_over);
+        route.post("/api/lease/release").to(lease::release);
+        route.get("/api/config").to(get_config);
+        route.post("/api/config/save").to(save_config);
+        route.get("/api/config/history").to(config_history);
+        route.get("/api/profiles").to(list_profiles);
+        route
+            .post("/api/profile/:name")
+            .with_path_extractor::<ProfilePath>()
+            .to(select_profile);
+        route
+            .post("/api/config/restore/:timestamp")
+            .with_path_extractor::<BackupPath>()
+            .to(restore_config);
+    })
+}
@@ -0,0 +1,197 @@
+use std::io::{self, Cursor, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use gotham::handler::HandlerResult;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::CONTENT_DISPOSITION;
+use gotham::hyper::{Body, Response, StatusCode};
+use gotham::mime;
+use gotham::prelude::*;
+use gotham::state::State;
+use opencv::core::{Mat, Vector};
+use opencv::imgcodecs::imencode;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::path::Angle;
+
+/// Time to wait for the pathfinder to process a frame.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Everything the pathfinder saw and decided for one frame.
+pub struct Snapshot {
+    /// Frame as read from the camera.
+    pub bgr: Mat,
+    /// Region of interest in HSV.
+    pub hsv_roi: Mat,
+    /// Masks of each class, by name.
+    pub masks: Vec<(&'static str, Mat)>,
+    /// Config in effect, as TOML.
+    pub config: String,
+    /// Angle chosen for the frame.
+    pub angle: Angle,
+}
+
+impl Snapshot {
+    /// Writes the images as PNGs into a zip with the config and angle.
+    pub fn to_zip(&self) -> io::Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+        let images = [("bgr", &self.bgr), ("hsv_roi", &self.hsv_roi)]
+            .into_iter()
+            .chain(self.masks.iter().map(|(name, mask)| (*name, mask)));
+        for (name, img) in images {
+            let mut png = Vector::<u8>::new();
+            imencode(".png", img, &mut png, &Vector::new())
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            zip.start_file(format!("{name}.png"), options)?;
+            zip.write_all(png.as_slice())?;
+        }
+
+        zip.start_file("config.toml", options)?;
+        zip.write_all(self.config.as_bytes())?;
+        zip.start_file("angle.txt", options)?;
+        zip.write_all(self.angle.to_string().as_bytes())?;
+
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+/// Someone waiting for the pathfinder, shared so they can withdraw if they stop waiting.
+type Request = Arc<Sender<Result<Vec<u8>, String>>>;
+
+/// Passes snapshot requests from the remote to the pathfinder.
+#[derive(Clone, Default, StateData)]
+pub struct SnapshotControl {
+    requests: Arc<Mutex<Vec<Request>>>,
+    /// Requests for just the camera frame, as a JPEG.
+    frame_requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl SnapshotControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if a snapshot of the next frame has been requested.
+    pub fn requested(&self) -> bool {
+        !self.requests.lock().unwrap().is_empty()
+    }
+
+    /// Sends a zipped snapshot to everyone who requested one.
+    pub fn fulfil(&self, snapshot: Snapshot) {
+        let zip = snapshot.to_zip().map_err(|err| err.to_string());
+        for request in self.requests.lock().unwrap().drain(..) {
+            let _ = request.send(zip.clone());
+        }
+    }
+
+    /// Returns true if a JPEG of the next frame has been requested.
+    pub fn frame_requested(&self) -> bool {
+        !self.frame_requests.lock().unwrap().is_empty()
+    }
+
+    /// Sends the camera frame as a JPEG to everyone who requested one.
+    pub fn fulfil_frame(&self, bgr: &Mat) {
+        let mut jpeg = Vector::<u8>::new();
+        let jpeg = imencode(".jpg", bgr, &mut jpeg, &Vector::new())
+            .map(|_| jpeg.to_vec())
+            .map_err(|err| err.to_string());
+        for request in self.frame_requests.lock().unwrap().drain(..) {
+            let _ = request.send(jpeg.clone());
+        }
+    }
+
+    /// Waits for the pathfinder to read its next frame.
+    /// Returns the frame as a JPEG.
+    pub fn take_frame(&self) -> Taken {
+        wait_in(&self.frame_requests)
+    }
+
+    /// Waits for the pathfinder to snapshot its next frame.
+    /// Returns the zipped snapshot.
+    pub fn take(&self) -> Taken {
+        wait_in(&self.requests)
+    }
+}
+
+/// Adds a request to the queue and waits for it to be fulfilled.
+/// Withdraws it on timeout so the pathfinder doesn't do the work for nobody.
+fn wait_in(requests: &Mutex<Vec<Request>>) -> Taken {
+    let (sender, receiver) = mpsc::channel();
+    let sender = Arc::new(sender);
+    requests.lock().unwrap().push(sender.clone());
+    let taken = receiver.recv_timeout(SNAPSHOT_TIMEOUT);
+    if taken.is_err() {
+        requests
+            .lock()
+            .unwrap()
+            .retain(|request| !Arc::ptr_eq(request, &sender));
+    }
+    taken
+}
+
+/// Outcome of waiting for the pathfinder.
+type Taken = Result<Result<Vec<u8>, String>, RecvTimeoutError>;
+
+/// Waits for the pathfinder on a blocking thread, so the runtime keeps serving other requests.
+async fn wait_for(take: impl FnOnce() -> Taken + Send + 'static) -> Taken {
+    tokio::task::spawn_blocking(take)
+        .await
+        .unwrap_or(Err(RecvTimeoutError::Disconnected))
+}
+
+pub async fn serve_snapshot(state: State) -> HandlerResult {
+    let snapshots = SnapshotControl::borrow_from(&state).clone();
+    let res = match wait_for(move || snapshots.take()).await {
+        Ok(Ok(zip)) => {
+            let mut res = create_response(
+                &state,
+                StatusCode::OK,
+                "application/zip".parse::<mime::Mime>().unwrap(),
+                zip,
+            );
+            res.headers_mut().insert(
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"snapshot.zip\"".parse().unwrap(),
+            );
+            res
+        }
+        Ok(Err(err)) => create_response(
+            &state,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            mime::TEXT_PLAIN,
+            format!("Failed to encode snapshot: {err}"),
+        ),
+        Err(_) => create_response(
+            &state,
+            StatusCode::SERVICE_UNAVAILABLE,
+            mime::TEXT_PLAIN,
+            "No frame was processed in time, is the car driving?",
+        ),
+    };
+    Ok((state, res))
+}
+
+pub async fn serve_frame(state: State) -> HandlerResult {
+    let snapshots = SnapshotControl::borrow_from(&state).clone();
+    let res = match wait_for(move || snapshots.take_frame()).await {
+        Ok(Ok(jpeg)) => create_response(&state, StatusCode::OK, mime::IMAGE_JPEG, jpeg),
+        Ok(Err(err)) => create_response(
+            &state,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            mime::TEXT_PLAIN,
+            format!("Failed to encode frame: {err}"),
+        ),
+        Err(_) => create_response(
+            &state,
+            StatusCode::SERVICE_UNAVAILABLE,
+            mime::TEXT_PLAIN,
+            "No frame was read in time, is the camera running?",
+        ),
+    };
+    Ok((state, res))
+}
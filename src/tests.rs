@@ -1,6 +1,11 @@
-use crate::{motor::Drivable, path};
-use opencv::core::{Mat, Point, VecN};
-use opencv::imgproc::{circle, LINE_8};
+use crate::{
+    adaptive, auth, calibrate, camera, cleanup, config, foxglove, ipc, lease, logging, metrics,
+    motor::Drivable, path, perspective, record, remote, snapshot, udp,
+};
+use opencv::core::{
+    perspective_transform, Mat, Point, Point2f, Rect, Scalar, VecN, Vector, CV_8UC1, CV_8UC3,
+};
+use opencv::imgproc::{circle, rectangle, FILLED, LINE_8};
 use opencv::prelude::*;
 use opencv::videoio::{VideoCapture, CAP_ANY};
 
@@ -42,6 +47,51 @@ impl Drivable for DummyCar {
     fn stop(&mut self) {}
 }
 
+/// Dummy car that takes a while to enable, like a real one initialising its motors.
+pub struct SlowCar {
+    car: DummyCar,
+    startup: std::time::Duration,
+}
+
+impl Drivable for SlowCar {
+    fn angle(&mut self, angle: crate::path::Angle, speed: crate::motor::Percent) {
+        self.car.angle(angle, speed);
+    }
+
+    fn disable(&mut self) {
+        self.car.disable();
+    }
+
+    fn enable(&mut self) {
+        self.car.enable();
+        self.init();
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.car.is_enabled()
+    }
+
+    fn drive_left(&mut self, duty_cycle: f64) {
+        self.car.drive_left(duty_cycle);
+    }
+
+    fn drive_right(&mut self, duty_cycle: f64) {
+        self.car.drive_right(duty_cycle);
+    }
+
+    fn forward(&mut self, speed: crate::motor::Percent) {
+        self.car.forward(speed);
+    }
+
+    fn init(&mut self) {
+        std::thread::sleep(self.startup);
+    }
+
+    fn stop(&mut self) {
+        self.car.stop();
+    }
+}
+
 pub fn draw_ray(img: &mut Mat, angle: &path::Angle, color: VecN<f64, 4>) {
     for point in path::cast_ray(&img.cols(), &img.rows(), angle) {
         circle(
@@ -61,6 +111,24 @@ pub fn debug_in() -> VideoCapture {
     VideoCapture::from_file("/home/linus/media/track.mp4", CAP_ANY).unwrap()
 }
 
+/// Writes content to a temporary config file named after the test, reads it and removes it.
+pub fn with_config<T>(name: &str, content: &str, read: impl FnOnce(&str) -> T) -> T {
+    let path = std::env::temp_dir().join(format!("io-{name}-{}.toml", std::process::id()));
+    let path = path.to_str().unwrap();
+    std::fs::write(path, content).unwrap();
+    let result = read(path);
+    std::fs::remove_file(path).unwrap();
+    result
+}
+
+/// Returns the key and line of each config error.
+pub fn error_keys(errors: &[config::ConfigError]) -> Vec<(&str, Option<usize>)> {
+    errors
+        .iter()
+        .map(|err| (err.key.as_str(), err.line))
+        .collect()
+}
+
 // #[test]
 // pub fn test_row_cluster_indices() {
 //     let mut cap = VideoCapture::from_file("/home/linus/media/track.mp4", CAP_ANY)
@@ -92,6 +160,1032 @@ pub fn test_point_dist() {
     assert_eq!(path::point_dist(&(1.0, 1.0), &(2.0, 2.0)), f32::sqrt(2.0));
 }
 
+#[test]
+pub fn test_presented_token() {
+    use gotham::hyper::header::{HeaderValue, AUTHORIZATION, COOKIE};
+    use gotham::hyper::HeaderMap;
+
+    let mut headers = HeaderMap::new();
+    assert_eq!(auth::presented_token(&headers), None);
+
+    headers.insert(COOKIE, HeaderValue::from_static("theme=dark; token=secret"));
+    assert_eq!(auth::presented_token(&headers), Some("secret"));
+
+    headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer other"));
+    assert_eq!(auth::presented_token(&headers), Some("other"));
+}
+
+#[test]
+pub fn test_heartbeat_after_slow_enable() {
+    use std::time::{Duration, Instant};
+
+    let startup = Duration::from_millis(200);
+    let mut car = remote::CarControl::new(SlowCar {
+        car: DummyCar::new(),
+        startup,
+    });
+    let timeout = Duration::from_millis(100);
+    let started = Instant::now();
+    car.enable();
+    // The timeout is counted from when the car finished starting.
+    car.check_heartbeat(timeout, started + startup + timeout);
+    assert!(car.is_enabled());
+    assert_eq!(car.fault(), None);
+    car.check_heartbeat(timeout, Instant::now() + timeout * 2);
+    assert!(!car.is_enabled());
+    assert_eq!(car.fault(), Some(remote::Fault::HeartbeatLost));
+}
+
+#[test]
+pub fn test_heartbeat_from_non_holder() {
+    use gotham::hyper::header::HeaderValue;
+    use gotham::hyper::StatusCode;
+    use gotham::test::TestServer;
+    use std::time::{Duration, Instant};
+
+    let config = with_config("holder", "speed = 0.2\n", |path| {
+        remote::ConfigControl::from_toml(path, None).unwrap()
+    });
+    let mut car = remote::CarControl::new(DummyCar::new());
+    let lease = lease::LeaseControl::default();
+    lease.claim("alice", false);
+    let server = TestServer::new(remote::router(
+        car.clone(),
+        config,
+        auth::AuthConfig::disabled(),
+        crate::snapshot::SnapshotControl::new(),
+        lease,
+    ))
+    .unwrap();
+    let beat = |client: &'static str| {
+        server
+            .client()
+            .post(
+                "http://localhost/api/heartbeat",
+                "",
+                gotham::mime::TEXT_PLAIN,
+            )
+            .with_header("x-controller", HeaderValue::from_static(client))
+            .perform()
+            .unwrap()
+            .status()
+    };
+    let timeout = Duration::from_millis(100);
+
+    car.enable();
+    let sent = Instant::now();
+    assert_eq!(beat("bob"), StatusCode::CONFLICT);
+    // Only the heartbeat from enabling counts, so it has lapsed.
+    car.check_heartbeat(timeout, sent + timeout);
+    assert!(!car.is_enabled());
+    assert_eq!(car.fault(), Some(remote::Fault::HeartbeatLost));
+
+    car.enable();
+    let sent = Instant::now();
+    assert_eq!(beat("alice"), StatusCode::OK);
+    car.check_heartbeat(timeout, sent + timeout);
+    assert!(car.is_enabled());
+}
+
+#[test]
+pub fn test_udp_packet_round_trip() {
+    let packets = [
+        udp::Packet {
+            seq: 1,
+            msg: udp::Message::Stop {
+                token: "secret".to_string(),
+            },
+        },
+        udp::Packet {
+            seq: 2,
+            msg: udp::Message::Teleop {
+                token: String::new(),
+                angle: -45.0,
+                speed: 30,
+            },
+        },
+        udp::Packet {
+            seq: u32::MAX,
+            msg: udp::Message::Telemetry(udp::Telemetry {
+                enabled: true,
+                angle: 12.5,
+                speed: 40.0,
+                left_duty_cycle: 0.07,
+                right_duty_cycle: 0.08,
+            }),
+        },
+    ];
+    for packet in packets {
+        assert_eq!(udp::Packet::decode(&packet.encode()), Ok(packet));
+    }
+
+    let mut wrong_version = udp::Packet {
+        seq: 1,
+        msg: udp::Message::Ack,
+    }
+    .encode();
+    wrong_version[2] = udp::VERSION + 1;
+    assert!(udp::Packet::decode(&wrong_version).is_err());
+}
+
+#[test]
+pub fn test_udp_commands() {
+    let mut car = remote::CarControl::new(DummyCar::new());
+    let mut watched = car.clone();
+    let lease = lease::LeaseControl::default();
+    let mut handle = |client: &str, msg: udp::Message| {
+        let packet = udp::Packet { seq: 1, msg };
+        udp::handle_packet(&mut car, &lease, client, &packet, "secret", |_| true)
+    };
+    let token = || "secret".to_string();
+    let rejected = |reply: Option<udp::Message>| matches!(reply, Some(udp::Message::Rejected(_)));
+
+    assert!(rejected(handle(
+        "udp a",
+        udp::Message::Arm {
+            token: "guess".to_string()
+        }
+    )));
+    assert_eq!(
+        handle("udp a", udp::Message::Arm { token: token() }),
+        Some(udp::Message::Ack)
+    );
+    for angle in [f64::NAN, 90.5, -120.0] {
+        assert!(rejected(handle(
+            "udp a",
+            udp::Message::Teleop {
+                token: token(),
+                angle,
+                speed: 20
+            }
+        )));
+    }
+    for speed in [127, -128] {
+        assert!(rejected(handle(
+            "udp a",
+            udp::Message::Teleop {
+                token: token(),
+                angle: 0.0,
+                speed
+            }
+        )));
+    }
+    // Subscribing doesn't keep the car alive for anyone but its controller.
+    let timeout = std::time::Duration::from_millis(100);
+    let sent = std::time::Instant::now();
+    assert_eq!(
+        handle("udp a", udp::Message::Subscribe { token: token() }),
+        Some(udp::Message::Ack)
+    );
+    watched.check_heartbeat(timeout, sent + timeout);
+    assert!(watched.is_enabled());
+    let sent = std::time::Instant::now();
+    assert_eq!(
+        handle("udp b", udp::Message::Subscribe { token: token() }),
+        Some(udp::Message::Ack)
+    );
+    watched.check_heartbeat(timeout, sent + timeout);
+    assert!(!watched.is_enabled());
+
+    // Control stays with the client that armed the car.
+    assert!(rejected(handle(
+        "udp b",
+        udp::Message::Teleop {
+            token: token(),
+            angle: 0.0,
+            speed: 20
+        }
+    )));
+    assert_eq!(
+        handle("udp b", udp::Message::Stop { token: token() }),
+        Some(udp::Message::Ack)
+    );
+    assert!(!car.is_enabled());
+}
+
+#[test]
+pub fn test_ipc_request() {
+    let mut car = DummyCar::new();
+    let status = ipc::parse_reply(&ipc::handle_request(&mut car, "enable")).unwrap();
+    assert!(status.enabled);
+    assert!(ipc::parse_reply(&ipc::handle_request(&mut car, "angle -30.5 40")).is_ok());
+    assert!(ipc::parse_reply(&ipc::handle_request(&mut car, "angle left 40")).is_err());
+    assert!(ipc::parse_reply(&ipc::handle_request(&mut car, "reverse")).is_err());
+    let status = ipc::parse_reply(&ipc::handle_request(&mut car, "disable")).unwrap();
+    assert!(!status.enabled);
+}
+
+#[test]
+pub fn test_ipc_disconnect() {
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join(format!("io-motor-{}.sock", std::process::id()));
+    let endpoint = ipc::Endpoint::Unix(path.to_string_lossy().into_owned());
+    let config = |address| ipc::DaemonConfig {
+        address,
+        watchdog: Duration::from_secs(10),
+    };
+    // TCP is refused without a token to check.
+    let tcp = ipc::Endpoint::Tcp("127.0.0.1:0".to_string());
+    assert!(ipc::serve(DummyCar::new(), config(tcp), None).is_err());
+
+    let daemon = config(endpoint.clone());
+    std::thread::spawn(move || ipc::serve(DummyCar::new(), daemon, None));
+    let connect = || loop {
+        match ipc::RemoteCar::connect(endpoint.clone(), None) {
+            Ok(car) => return car,
+            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    let mut driver = connect();
+    driver.enable();
+    assert!(driver.is_enabled());
+
+    // Other clients leave the car to its controller.
+    drop(connect());
+    std::thread::sleep(Duration::from_millis(50));
+    driver.forward(10);
+    assert!(driver.is_enabled());
+
+    drop(driver);
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!connect().is_enabled());
+    std::fs::remove_file(path).unwrap();
+}
+
+/// Reads fields of an MCAP record in order.
+struct McapFields<'a>(&'a [u8]);
+
+impl<'a> McapFields<'a> {
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        field
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take(2).try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take(4).try_into().unwrap())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take(8).try_into().unwrap())
+    }
+
+    fn bytes(&mut self) -> &'a [u8] {
+        let len = self.u32() as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> &'a str {
+        std::str::from_utf8(self.bytes()).unwrap()
+    }
+}
+
+#[test]
+pub fn test_record_mcap() {
+    let dir = std::env::temp_dir().join(format!("io-record-{}", std::process::id()));
+    let config = record::RecordConfig {
+        dir: dir.to_string_lossy().into_owned(),
+    };
+    let path = record::start(&config).unwrap();
+    assert!(record::recording());
+    let payload = b"{\"angle\":0}";
+    record::record(&foxglove::ANGLE, 1, payload);
+    record::record(&foxglove::ANGLE, 2, payload);
+    assert_eq!(record::finish(), Some(path.clone()));
+    assert!(!record::recording());
+
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    let magic = b"\x89MCAP0\r\n";
+    assert_eq!(&bytes[..8], magic);
+    assert_eq!(&bytes[bytes.len() - 8..], magic);
+
+    // Split the data section into records of an opcode and its fields.
+    let mut records = vec![];
+    let mut rest = McapFields(&bytes[8..bytes.len() - 8]);
+    while !rest.0.is_empty() {
+        let op = rest.take(1)[0];
+        let len = rest.u64() as usize;
+        records.push((op, McapFields(rest.take(len))));
+    }
+    let mut records = records.into_iter();
+
+    let (op, mut header) = records.next().unwrap();
+    assert_eq!(op, 0x01);
+    assert_eq!((header.str(), header.str()), ("", "immovable-object"));
+    assert!(header.0.is_empty());
+
+    for channel in foxglove::CHANNELS {
+        let (op, mut schema) = records.next().unwrap();
+        assert_eq!(op, 0x03);
+        assert_eq!(schema.u16() as u32, channel.id);
+        assert_eq!(schema.str(), channel.schema_name);
+        assert_eq!(schema.str(), "jsonschema");
+        assert_eq!(schema.bytes(), channel.schema.as_bytes());
+        assert!(schema.0.is_empty());
+
+        let (op, mut fields) = records.next().unwrap();
+        assert_eq!(op, 0x04);
+        assert_eq!(fields.u16() as u32, channel.id);
+        // Schema id.
+        assert_eq!(fields.u16() as u32, channel.id);
+        assert_eq!(fields.str(), channel.topic);
+        assert_eq!(fields.str(), "json");
+        // Empty metadata map.
+        assert_eq!(fields.u32(), 0);
+        assert!(fields.0.is_empty());
+    }
+
+    // Other tests may publish on other channels meanwhile.
+    let mut angles = vec![];
+    let (op, mut data_end) = loop {
+        let (op, mut message) = records.next().unwrap();
+        if op != 0x05 {
+            break (op, message);
+        }
+        let channel = message.u16() as u32;
+        // Sequence, log time and publish time.
+        let fields = (message.u32(), message.u64(), message.u64());
+        if channel == foxglove::ANGLE.id {
+            assert_eq!(message.0, payload);
+            angles.push(fields);
+        }
+    };
+    assert_eq!(angles, vec![(1, 1, 1), (2, 2, 2)]);
+    assert_eq!(op, 0x0F);
+    // No data section CRC.
+    assert_eq!(data_end.u32(), 0);
+    assert!(data_end.0.is_empty());
+
+    let (op, mut footer) = records.next().unwrap();
+    assert_eq!(op, 0x02);
+    // No summary section, so no summary offsets or CRC.
+    assert_eq!(footer.u64(), 0);
+    assert_eq!(footer.u64(), 0);
+    assert_eq!(footer.u32(), 0);
+    assert!(footer.0.is_empty());
+    assert!(records.next().is_none());
+}
+
+#[test]
+pub fn test_config_defaults() {
+    let config = config::parse(
+        "left_lower = [23, 40, 40]\nleft_upper = [37, 255, 255]\nspeed = 1\n[log]\nlevel = \"info\"\n",
+    )
+    .unwrap();
+    assert_eq!(config.left.ranges[0].lower, [23, 40, 40]);
+    assert!(config.right.ranges.is_empty());
+    assert_eq!(config.speed, 1.0);
+    assert_eq!(config.p_gain, 1.0);
+    assert_eq!(config.roi, config::DEFAULT_ROI);
+}
+
+#[test]
+pub fn test_config_errors() {
+    let errors = config::parse(
+        "left_lower = [180, 40, 40]\nright_upper = [150, 255]\np_gain = 1.5\np_gian = 0.5\n",
+    )
+    .unwrap_err();
+    assert_eq!(
+        error_keys(&errors),
+        vec![
+            ("left_lower[0]", Some(1)),
+            ("right_upper", Some(2)),
+            ("p_gain", Some(3)),
+            ("p_gian", Some(4)),
+        ]
+    );
+
+    let errors = config::parse("speed = [").unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].line, Some(1));
+}
+
+#[test]
+pub fn test_remote_args() {
+    let args = |args: &[&str]| {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<String>>()
+    };
+    let mut config = remote::RemoteConfig::default();
+    config
+        .apply_args(&args(&["--port", "8080", "--address", "127.0.0.1"]))
+        .unwrap();
+    assert_eq!((config.address.as_str(), config.port), ("127.0.0.1", 8080));
+    assert!(config.apply_args(&args(&["--port"])).is_err());
+    assert!(config.apply_args(&args(&["--port", "70000"])).is_err());
+    assert!(config.apply_args(&args(&["--verbose"])).is_err());
+}
+
+#[test]
+pub fn test_config_section_errors() {
+    let content =
+        "speed = 0.2\n[log]\nlevel = \"loud\"\nmax_bytes = -1\n[remote]\nport = 70000\n[udp]\n";
+    with_config("sections", content, |path| {
+        let errors = logging::LogConfig::from_toml(path).unwrap_err();
+        assert_eq!(
+            error_keys(&errors),
+            vec![("log.level", Some(3)), ("log.max_bytes", Some(4))]
+        );
+        let errors = remote::RemoteConfig::from_toml(path).unwrap_err();
+        assert_eq!(error_keys(&errors)[0], ("remote.port", Some(6)));
+        // Missing keys take their defaults, missing tables are left out.
+        assert_eq!(udp::UdpConfig::from_toml(path).unwrap().unwrap().port, 5005);
+        assert!(foxglove::FoxgloveConfig::from_toml(path).unwrap().is_none());
+    });
+}
+
+#[test]
+pub fn test_config_diff() {
+    let old =
+        config::parse("speed = 0.2\nleft_lower = [23, 40, 40]\nleft_upper = [37, 255, 255]\n")
+            .unwrap();
+    let new =
+        config::parse("speed = 0.3\nleft_lower = [23, 40, 40]\nleft_upper = [37, 255, 255]\n")
+            .unwrap();
+    assert_eq!(old.diff(&new), vec!["speed: 0.2 -> 0.3".to_string()]);
+    assert!(old.diff(&old.clone()).is_empty());
+}
+
+#[test]
+pub fn test_config_backup() {
+    with_config("backup", "speed = 0.2\n", |path| {
+        // Backups in the same millisecond don't overwrite each other.
+        let first = path::DrivableConfig::backup(path).unwrap();
+        let second = path::DrivableConfig::backup(path).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(path::DrivableConfig::history(path).unwrap().len(), 2);
+
+        let config = config::parse("speed = 0.3\n").unwrap();
+        config.save_toml(path).unwrap();
+        assert!(!std::path::Path::new(&format!("{path}.tmp")).exists());
+        assert_eq!(path::DrivableConfig::history(path).unwrap().len(), 3);
+        let restored = path::DrivableConfig::restore(path, first, config::BASE_PROFILE).unwrap();
+        assert_eq!(restored.speed, 0.2);
+        std::fs::remove_dir_all(format!("{path}.history")).unwrap();
+    });
+}
+
+#[test]
+pub fn test_config_profiles() {
+    let content = "speed = 0.2\nleft_lower = [23, 40, 40]\nleft_upper = [37, 255, 255]\n\
+        profile = \"lab\"\n\
+        [profiles.lab]\nspeed = 0.3\n\
+        [profiles.evening]\ninherits = \"lab\"\nleft_lower = [20, 60, 30]\n";
+    let lab = config::parse(content).unwrap();
+    assert_eq!((lab.profile.as_str(), lab.speed), ("lab", 0.3));
+    assert_eq!(lab.left.ranges[0].lower, [23, 40, 40]);
+    let evening = config::parse_profile(content, Some("evening")).unwrap();
+    assert_eq!(evening.speed, 0.3);
+    assert_eq!(evening.left.ranges[0].lower, [20, 60, 30]);
+    assert_eq!(evening.left.ranges[0].upper, [37, 255, 255]);
+    let base = config::parse_profile(content, Some(config::BASE_PROFILE)).unwrap();
+    assert_eq!(base.speed, 0.2);
+    assert_eq!(config::profiles(content), vec!["base", "evening", "lab"]);
+
+    let errors = config::parse_profile(
+        "[profiles.a]\ninherits = \"b\"\n[profiles.b]\ninherits = \"a\"\n",
+        Some("a"),
+    )
+    .unwrap_err();
+    assert_eq!(errors[0].key, "profiles.b.inherits");
+    assert_eq!(errors[0].line, Some(4));
+    assert!(config::parse_profile(content, Some("missing")).is_err());
+}
+
+#[test]
+pub fn test_config_setting() {
+    let content = "speed = 0.2\nroi = [0, 230, 640, 250]\nprofile = \"lab\"\n\
+        [profiles.lab]\nspeed = 0.3\n\
+        [profiles.evening]\ninherits = \"lab\"\nroi = [0, 200, 640, 280]\n";
+    assert_eq!(
+        config::setting(content, "lab", "roi"),
+        Some(("roi".to_string(), 2))
+    );
+    assert_eq!(
+        config::setting(content, "evening", "roi"),
+        Some(("profiles.evening.roi".to_string(), 8))
+    );
+    assert_eq!(config::setting(content, "lab", "p_gain"), None);
+}
+
+#[test]
+pub fn test_config_ranges() {
+    let config = config::parse(
+        "box_lower = [[170, 80, 80], [100, 80, 80]]\nbox_upper = [[10, 255, 255], [120, 255, 255]]\n\
+        car_space = \"lab\"\ncar_lower = [200, 0, 0]\ncar_upper = [100, 255, 255]\n",
+    )
+    .unwrap();
+    assert_eq!(
+        config.boxes.bounds(),
+        vec![
+            ([170, 80, 80], [179, 255, 255]),
+            ([0, 80, 80], [10, 255, 255]),
+            ([100, 80, 80], [120, 255, 255])
+        ]
+    );
+    // Only hues wrap around.
+    assert_eq!(config.cars.space, path::ColourSpace::Lab);
+    assert_eq!(config.cars.bounds(), vec![([200, 0, 0], [100, 255, 255])]);
+    let reparsed = config::parse(&config.to_toml()).unwrap();
+    assert_eq!((reparsed.boxes, reparsed.cars), (config.boxes, config.cars));
+
+    let errors = config::parse("car_lower = [[0, 0, 0], [10, 0, 0]]\ncar_upper = [5, 255, 255]\n")
+        .unwrap_err();
+    assert_eq!(errors[0].key, "car_lower");
+}
+
+#[test]
+pub fn test_calibrate_fit_range() {
+    // Reds either side of hue 0, with a stray pixel.
+    let mut reds = vec![];
+    for hue in [170, 175, 179, 0, 5, 10] {
+        for saturation in 100..110 {
+            reds.push([hue, saturation, 200]);
+        }
+    }
+    reds.push([90, 100, 200]);
+    let range = calibrate::fit_range(&reds, path::ColourSpace::Hsv, 1.0);
+    assert_eq!(range.lower, [170, 100, 200]);
+    assert_eq!(range.upper, [10, 109, 200]);
+
+    let thresholds = path::Thresholds {
+        space: path::ColourSpace::Hsv,
+        ranges: vec![range],
+    };
+    assert!(calibrate::coverage(&thresholds, &reds) > 0.98);
+    assert_eq!(calibrate::coverage(&thresholds, &[[90, 150, 200]]), 0.0);
+}
+
+#[test]
+pub fn test_adaptive_tracker() {
+    let hsv = path::ColourSpace::Hsv;
+    let configured = path::ColourRange {
+        lower: [175, 40, 200],
+        upper: [5, 255, 255],
+    };
+    let mut tracker = adaptive::Tracker::new(configured);
+    assert_eq!(tracker.range(hsv, [10, 40, 40]), configured);
+
+    // Reds drifting across hue 0 and getting brighter.
+    tracker.update([178.0, 100.0, 220.0], hsv, 0.5);
+    for _ in 0..20 {
+        tracker.update([2.0, 100.0, 250.0], hsv, 0.5);
+    }
+    assert_eq!(tracker.shift(hsv, [10, 40, 40]), [4, 0, 30]);
+    let range = tracker.range(hsv, [10, 40, 40]);
+    assert_eq!(range.lower, [179, 40, 230]);
+    // Values stop at their maximum rather than wrapping.
+    assert_eq!(range.upper, [9, 255, 255]);
+
+    // Shifts are no more than max_drift.
+    assert_eq!(tracker.shift(hsv, [2, 40, 10]), [2, 0, 10]);
+}
+
+#[test]
+pub fn test_adaptive_config() {
+    let content = "[adaptive]\nrate = 1\nmin_pixels = 50\n";
+    let config = with_config("adaptive", content, adaptive::AdaptiveConfig::from_toml)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        (config.rate, config.max_drift, config.min_pixels),
+        (1.0, [10, 40, 40], 50)
+    );
+
+    let content = "[adaptive]\nrate = 0\nmax_drift = [10, 300, 40]\n";
+    let errors = with_config("adaptive", content, adaptive::AdaptiveConfig::from_toml).unwrap_err();
+    assert_eq!(
+        error_keys(&errors),
+        vec![("adaptive.rate", Some(2)), ("adaptive.max_drift", Some(3))]
+    );
+}
+
+#[test]
+pub fn test_cleanup() {
+    let mut mask = Mat::new_rows_cols_with_default(60, 60, CV_8UC1, Scalar::all(0.0)).unwrap();
+    let white = Scalar::all(255.0);
+    for rect in [
+        // Speck, bar and block.
+        Rect::new(2, 2, 1, 1),
+        Rect::new(10, 10, 3, 30),
+        Rect::new(30, 30, 10, 10),
+    ] {
+        rectangle(&mut mask, rect, white, FILLED, LINE_8, 0).unwrap();
+    }
+    let at = |mask: &Mat, x, y| *mask.at_2d::<u8>(y, x).unwrap();
+
+    let mut cleanup = cleanup::Cleanup {
+        min_area: 5,
+        ..Default::default()
+    };
+    let cleaned = cleanup.clean(&mask);
+    assert_eq!(at(&cleaned, 2, 2), 0);
+    assert_eq!(at(&cleaned, 11, 20), 255);
+    assert_eq!(at(&cleaned, 35, 35), 255);
+
+    cleanup.max_aspect = 4.0;
+    let cleaned = cleanup.clean(&mask);
+    assert_eq!(at(&cleaned, 11, 20), 0);
+    assert_eq!(at(&cleaned, 35, 35), 255);
+
+    // Opening with a kernel wider than the bar removes it too.
+    let cleanup = cleanup::Cleanup {
+        open: 5,
+        ..Default::default()
+    };
+    let cleaned = cleanup.clean(&mask);
+    assert_eq!(at(&cleaned, 2, 2), 0);
+    assert_eq!(at(&cleaned, 11, 20), 0);
+    assert_eq!(at(&cleaned, 35, 35), 255);
+}
+
+#[test]
+pub fn test_cleanup_config() {
+    let content = "[cleanup]\nclose = 5\n[cleanup.box]\nmax_aspect = 3\n";
+    let config = with_config("cleanup", content, cleanup::CleanupConfig::from_toml)
+        .unwrap()
+        .unwrap();
+    // Keys that are not set take the defaults thresholds.toml documents.
+    let base = cleanup::Cleanup {
+        open: 3,
+        close: 5,
+        min_area: 20,
+        max_aspect: 0.0,
+    };
+    assert_eq!(config.classes["left"], base);
+    assert_eq!(
+        config.classes["box"],
+        cleanup::Cleanup {
+            max_aspect: 3.0,
+            ..base
+        }
+    );
+
+    let content = "[cleanup]\nopen = 300\n[cleanup.boxes]\n[cleanup.car]\nmax_aspect = 0.5\n";
+    let errors = with_config("cleanup", content, cleanup::CleanupConfig::from_toml).unwrap_err();
+    assert_eq!(
+        error_keys(&errors),
+        vec![
+            ("cleanup.boxes", None),
+            ("cleanup.open", Some(2)),
+            ("cleanup.car.max_aspect", Some(5)),
+        ]
+    );
+}
+
+#[test]
+pub fn test_perspective() {
+    let config = perspective::PerspectiveConfig {
+        image: [
+            (220.0, 300.0),
+            (420.0, 300.0),
+            (600.0, 470.0),
+            (40.0, 470.0),
+        ],
+        ground: [(-30.0, 80.0), (30.0, 80.0), (30.0, 20.0), (-30.0, 20.0)],
+        width: 120.0,
+        length: 150.0,
+        cm_per_pixel: 0.5,
+        stop_distance: 30.0,
+        near_distance: 150.0,
+    };
+    assert_eq!(config.size().width, 240);
+    assert_eq!(config.size().height, 300);
+    // The car is at the bottom centre of the view.
+    assert_eq!(config.view_point((0.0, 0.0)), (120.0, 300.0));
+
+    // Points in the region of interest land where they are on the ground.
+    let roi = Rect::new(0, 230, 640, 250);
+    let image = config
+        .image
+        .iter()
+        .map(|(x, y)| Point2f::new(*x, y - 230.0))
+        .collect::<Vector<Point2f>>();
+    let mut view = Vector::<Point2f>::new();
+    perspective_transform(&image, &mut view, &config.homography(roi)).unwrap();
+    for (point, ground) in view.iter().zip(config.ground) {
+        let (x, y) = config.view_point(ground);
+        assert!((point.x - x).abs() < 0.01 && (point.y - y).abs() < 0.01);
+    }
+}
+
+#[test]
+pub fn test_perspective_config() {
+    let content = "[perspective]\nimage = [[220, 300], [420, 300], [600, 470], [40, 470]]\n\
+        ground = [[-30, 80], [30, 80], [30, 20], [-30, 20]]\n";
+    let config = with_config(
+        "perspective",
+        content,
+        perspective::PerspectiveConfig::from_toml,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!((config.width, config.length), (60.0, 80.0));
+    // The finish can't be seen nearer than the nearest point.
+    assert_eq!((config.stop_distance, config.near_distance), (30.0, 80.0));
+
+    let content = "[perspective]\nimage = [[0, 0], [0, 0], [5, 5], [9, 2]]\n\
+        ground = [[-30, 80], [30, 80], [30, 20], [-30, 20]]\ncm_per_pixel = 0\n";
+    let errors = with_config(
+        "perspective",
+        content,
+        perspective::PerspectiveConfig::from_toml,
+    )
+    .unwrap_err();
+    assert_eq!(
+        error_keys(&errors),
+        vec![
+            ("perspective.image", Some(2)),
+            ("perspective.cm_per_pixel", Some(4)),
+        ]
+    );
+    let content = "[perspective]\nwidth = 100\n";
+    let errors = with_config(
+        "perspective",
+        content,
+        perspective::PerspectiveConfig::from_toml,
+    )
+    .unwrap_err();
+    assert_eq!(errors.len(), 2);
+}
+
+#[test]
+pub fn test_choose_angle_from_above() {
+    let content = "finish_lower = [50, 100, 100]\nfinish_upper = [70, 255, 255]\n";
+    let config = with_config("above", content, |path| {
+        remote::ConfigControl::from_toml(path, None).unwrap()
+    });
+    let vision = path::VisionConfig {
+        perspective: Some(perspective::PerspectiveConfig {
+            image: [
+                (220.0, 300.0),
+                (420.0, 300.0),
+                (600.0, 470.0),
+                (40.0, 470.0),
+            ],
+            ground: [(-30.0, 80.0), (30.0, 80.0), (30.0, 20.0), (-30.0, 20.0)],
+            width: 120.0,
+            length: 150.0,
+            cm_per_pixel: 1.0,
+            stop_distance: 30.0,
+            near_distance: 150.0,
+        }),
+        ..Default::default()
+    };
+    let mut car = remote::CarControl::new(DummyCar::new());
+    car.enable();
+    let mut pathfinder = path::Pathfinder::new(
+        car.clone(),
+        config,
+        None,
+        crate::snapshot::SnapshotControl::new(),
+        None,
+        vision,
+    );
+    // A finish line across the frame, with its near edge at the given row.
+    let finish = |row: i32| {
+        let mut bgr = Mat::new_rows_cols_with_default(480, 640, CV_8UC3, Scalar::all(0.0)).unwrap();
+        let green = Scalar::new(0.0, 255.0, 0.0, 0.0);
+        rectangle(
+            &mut bgr,
+            Rect::new(0, row - 20, 640, 20),
+            green,
+            FILLED,
+            LINE_8,
+            0,
+        )
+        .unwrap();
+        bgr
+    };
+
+    // About 80cm ahead, so driven on.
+    assert_eq!(pathfinder.consider_frame(&finish(310)), 0.0);
+    assert!(car.is_enabled());
+    // At the bottom of the frame about 20cm ahead, the nearest it can be seen.
+    pathfinder.consider_frame(&finish(480));
+    assert!(!car.is_enabled());
+}
+
+#[test]
+pub fn test_camera_config() {
+    let config = camera::CameraConfig {
+        resolution: (640, 480),
+        matrix: [512.3456789, 510.0, 320.5, 241.25],
+        distortion: vec![-0.3, 0.1, 0.0, 0.0, -0.02],
+    };
+    let read = with_config("camera", &config.to_toml(), camera::CameraConfig::from_toml)
+        .unwrap()
+        .unwrap();
+    let content = "[camera]\nresolution = [640, 480]\ndistortion = [0.1]\n";
+    let errors = with_config("camera", content, camera::CameraConfig::from_toml).unwrap_err();
+    assert_eq!(
+        error_keys(&errors),
+        vec![("camera.matrix", None), ("camera.distortion", Some(3))]
+    );
+    assert_eq!(read.resolution, config.resolution);
+    // Written to a millionth.
+    assert_eq!(read.matrix, [512.345679, 510.0, 320.5, 241.25]);
+    assert_eq!(read.distortion, config.distortion);
+}
+
+#[test]
+pub fn test_lease() {
+    use std::time::{Duration, Instant};
+
+    let lease = lease::LeaseControl::default();
+    let start = Instant::now();
+    assert!(lease.holder_at(start).is_none());
+    assert!(matches!(
+        lease.claim_at("alice", false, start),
+        lease::Claim::Granted
+    ));
+    assert!(matches!(
+        lease.claim_at("bob", false, start + Duration::from_secs(10)),
+        lease::Claim::Held(holder) if holder == "alice"
+    ));
+    // Claiming again extends the lease.
+    let renewed = start + Duration::from_secs(20);
+    assert!(matches!(
+        lease.claim_at("alice", false, renewed),
+        lease::Claim::Granted
+    ));
+    let (holder, remaining) = lease.holder_at(start + Duration::from_secs(40)).unwrap();
+    assert_eq!(holder, "alice");
+    assert_eq!(remaining, Duration::from_secs(10));
+
+    // Expires after 30s without commands.
+    let expired = renewed + Duration::from_secs(31);
+    assert!(lease.holder_at(expired).is_none());
+    assert!(matches!(
+        lease.claim_at("bob", false, expired),
+        lease::Claim::Granted
+    ));
+    assert_eq!(lease.holder_at(expired).unwrap().0, "bob");
+
+    // Takeover doesn't wait for the lease to expire.
+    assert!(matches!(
+        lease.claim_at("alice", true, expired),
+        lease::Claim::Granted
+    ));
+    assert_eq!(lease.holder_at(expired).unwrap().0, "alice");
+    // Only the holder can release it.
+    lease.release("bob");
+    assert!(lease.holder_at(expired).is_some());
+    lease.release("alice");
+    assert!(lease.holder_at(expired).is_none());
+}
+
+#[test]
+pub fn test_lease_client_name() {
+    use gotham::hyper::header::{HeaderValue, COOKIE};
+    use gotham::hyper::HeaderMap;
+
+    let mut headers = HeaderMap::new();
+    assert_eq!(lease::client_name(&headers), None);
+    headers.insert(
+        COOKIE,
+        HeaderValue::from_static("theme=dark; controller=bob"),
+    );
+    assert_eq!(lease::client_name(&headers), Some("bob"));
+    // The header wins over the cookie.
+    headers.insert(lease::CONTROLLER_HEADER, HeaderValue::from_static("alice"));
+    assert_eq!(lease::client_name(&headers), Some("alice"));
+    headers.insert(lease::CONTROLLER_HEADER, HeaderValue::from_static(""));
+    assert_eq!(lease::client_name(&headers), None);
+    headers.remove(lease::CONTROLLER_HEADER);
+    headers.insert(COOKIE, HeaderValue::from_static("controller="));
+    assert_eq!(lease::client_name(&headers), None);
+}
+
+#[test]
+pub fn test_metrics() {
+    let counter = metrics::Counter::new();
+    counter.inc();
+    counter.inc_by(2);
+    assert_eq!(counter.get(), 3);
+    let gauge = metrics::Gauge::new();
+    gauge.set(-1.5);
+    assert_eq!(gauge.get(), -1.5);
+
+    let histogram = metrics::Histogram::new(&[1.0, 10.0]);
+    let mut out = String::new();
+    histogram.render(&mut out, "empty");
+    assert_eq!(
+        out,
+        "empty_bucket{le=\"1\"} 0\n\
+         empty_bucket{le=\"10\"} 0\n\
+         empty_bucket{le=\"+Inf\"} 0\n\
+         empty_sum 0\n\
+         empty_count 0\n"
+    );
+    for val in [0.5, 3.0, 30.0] {
+        histogram.observe(val);
+    }
+    let mut out = String::new();
+    histogram.render(&mut out, "rays");
+    // Buckets are cumulative.
+    assert_eq!(
+        out,
+        "rays_bucket{le=\"1\"} 1\n\
+         rays_bucket{le=\"10\"} 2\n\
+         rays_bucket{le=\"+Inf\"} 3\n\
+         rays_sum 33.5\n\
+         rays_count 3\n"
+    );
+
+    // Other tests drive the global metrics, so only check the shape of their output.
+    let rendered = metrics::render();
+    for (name, kind) in [
+        ("io_frames_processed_total", "counter"),
+        ("io_frames_dropped_total", "counter"),
+        ("io_consider_frame_seconds", "histogram"),
+        ("io_rays_cast", "histogram"),
+        ("io_detections_total", "counter"),
+        ("io_angle", "gauge"),
+        ("io_speed", "gauge"),
+        ("io_duty_cycle", "gauge"),
+        ("io_transitions_total", "counter"),
+    ] {
+        assert!(rendered.contains(&format!("# HELP {name} ")), "{name}");
+        assert!(
+            rendered.contains(&format!("# TYPE {name} {kind}\n")),
+            "{name}"
+        );
+    }
+    for line in [
+        "io_consider_frame_seconds_bucket{le=\"0.005\"} ",
+        "io_consider_frame_seconds_bucket{le=\"+Inf\"} ",
+        "io_consider_frame_seconds_sum ",
+        "io_consider_frame_seconds_count ",
+        "io_rays_cast_bucket{le=\"100\"} ",
+        "io_detections_total{object=\"finish_line\"} ",
+        "io_duty_cycle{motor=\"left\"} ",
+        "io_transitions_total{transition=\"disable\"} ",
+    ] {
+        assert!(rendered.contains(line), "{line}");
+    }
+    // Every sample has a value.
+    for sample in rendered.lines().filter(|line| !line.starts_with('#')) {
+        let (_, val) = sample.rsplit_once(' ').unwrap();
+        assert!(val.parse::<f64>().is_ok(), "{sample}");
+    }
+}
+
+#[test]
+pub fn test_snapshot_zip() {
+    let snapshot = snapshot::Snapshot {
+        bgr: Mat::new_rows_cols_with_default(4, 4, CV_8UC3, Scalar::all(0.0)).unwrap(),
+        hsv_roi: Mat::new_rows_cols_with_default(2, 4, CV_8UC3, Scalar::all(0.0)).unwrap(),
+        masks: vec![
+            (
+                "line",
+                Mat::new_rows_cols_with_default(2, 4, CV_8UC1, Scalar::all(255.0)).unwrap(),
+            ),
+            (
+                "wall",
+                Mat::new_rows_cols_with_default(2, 4, CV_8UC1, Scalar::all(0.0)).unwrap(),
+            ),
+        ],
+        config: "speed = 0.2\n".to_string(),
+        angle: 12.5,
+    };
+    let zip = snapshot.to_zip().unwrap();
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zip)).unwrap();
+    let mut names = archive.file_names().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "angle.txt",
+            "bgr.png",
+            "config.toml",
+            "hsv_roi.png",
+            "line.png",
+            "wall.png"
+        ]
+    );
+    let mut angle = String::new();
+    std::io::Read::read_to_string(&mut archive.by_name("angle.txt").unwrap(), &mut angle).unwrap();
+    assert_eq!(angle, "12.5");
+}
+
+#[test]
+pub fn test_snapshot_timeout() {
+    let snapshots = snapshot::SnapshotControl::new();
+    assert!(snapshots.take().is_err());
+    // Nobody is waiting for it anymore.
+    assert!(!snapshots.requested());
+    assert!(snapshots.take_frame().is_err());
+    assert!(!snapshots.frame_requested());
+}
+
 // #[test]
 // pub fn test_get_combined_mask() {
 //     let mut cap = VideoCapture::from_file("/home/linus/media/track.mp4", CAP_ANY)
@@ -0,0 +1,501 @@
+//! Compact binary UDP protocol for controlling the car and receiving telemetry.
+//!
+//! Every packet starts with an 8 byte header:
+//!
+//! | bytes | field                              |
+//! |-------|------------------------------------|
+//! | 0..2  | magic, `b"IO"`                     |
+//! | 2     | protocol version, [`VERSION`]      |
+//! | 3     | message kind                       |
+//! | 4..8  | sequence number, big endian u32    |
+//!
+//! followed by the body for that kind. Multi-byte fields are big endian.
+//!
+//! | kind | message   | body                                                       |
+//! |------|-----------|------------------------------------------------------------|
+//! | 0    | Stop      | token                                                      |
+//! | 1    | Arm       | token                                                      |
+//! | 2    | Teleop    | token, angle f32, speed i8                                 |
+//! | 3    | Subscribe | token                                                      |
+//! | 16   | Ack       | none, seq is that of the acknowledged command              |
+//! | 17   | Telemetry | enabled u8, angle f32, speed f32, left f32, right f32      |
+//! | 18   | Rejected  | reason as UTF-8                                            |
+//!
+//! Tokens are a u8 length followed by that many bytes of UTF-8, and must match
+//! the token in [auth]. Commands with a sequence number no greater than the last
+//! seen from the same address are ignored, except Stop which is always obeyed.
+//!
+//! Arm and Teleop take control like the remote does, so are rejected while another
+//! client holds it. They and Subscribe count as heartbeats, so with [heartbeat] set
+//! the controlling client must keep sending one of them to keep the car enabled.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::auth::tokens_match;
+use crate::config::{self, ConfigError, Field};
+use crate::lease::{Claim, LeaseControl};
+use crate::metrics;
+use crate::motor::{Drivable, Percent};
+use crate::path::Angle;
+use crate::remote::CarControl;
+
+pub const MAGIC: [u8; 2] = *b"IO";
+pub const VERSION: u8 = 1;
+const HEADER_LEN: usize = 8;
+
+/// Time between telemetry packets.
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// Time a subscriber keeps receiving telemetry without sending anything.
+const SUBSCRIBER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages that can be sent over the protocol.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Message {
+    Stop {
+        token: String,
+    },
+    Arm {
+        token: String,
+    },
+    Teleop {
+        token: String,
+        angle: Angle,
+        speed: Percent,
+    },
+    Subscribe {
+        token: String,
+    },
+    Ack,
+    Telemetry(Telemetry),
+    Rejected(String),
+}
+
+/// State of the car broadcast to subscribers.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Telemetry {
+    pub enabled: bool,
+    pub angle: f32,
+    pub speed: f32,
+    pub left_duty_cycle: f32,
+    pub right_duty_cycle: f32,
+}
+
+/// Message with the sequence number it was sent with.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Packet {
+    pub seq: u32,
+    pub msg: Message,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32);
+        buf.extend(MAGIC);
+        buf.push(VERSION);
+        buf.push(self.msg.kind());
+        buf.extend(self.seq.to_be_bytes());
+        match &self.msg {
+            Message::Stop { token } | Message::Arm { token } | Message::Subscribe { token } => {
+                encode_token(&mut buf, token);
+            }
+            Message::Teleop {
+                token,
+                angle,
+                speed,
+            } => {
+                encode_token(&mut buf, token);
+                buf.extend((*angle as f32).to_be_bytes());
+                buf.push((*speed).clamp(-100, 100) as i8 as u8);
+            }
+            Message::Ack => {}
+            Message::Telemetry(telemetry) => {
+                buf.push(telemetry.enabled as u8);
+                for val in [
+                    telemetry.angle,
+                    telemetry.speed,
+                    telemetry.left_duty_cycle,
+                    telemetry.right_duty_cycle,
+                ] {
+                    buf.extend(val.to_be_bytes());
+                }
+            }
+            Message::Rejected(reason) => buf.extend(reason.as_bytes()),
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        if buf.len() < HEADER_LEN || buf[0..2] != MAGIC {
+            return Err("Not an IO packet.".to_string());
+        }
+        if buf[2] != VERSION {
+            return Err(format!(
+                "Unsupported protocol version {}, expected {VERSION}.",
+                buf[2]
+            ));
+        }
+        let seq = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+        let mut body = Reader(&buf[HEADER_LEN..]);
+        let msg = match buf[3] {
+            0 => Message::Stop {
+                token: body.token()?,
+            },
+            1 => Message::Arm {
+                token: body.token()?,
+            },
+            2 => Message::Teleop {
+                token: body.token()?,
+                angle: body.f32()? as Angle,
+                speed: body.u8()? as i8 as Percent,
+            },
+            3 => Message::Subscribe {
+                token: body.token()?,
+            },
+            16 => Message::Ack,
+            17 => Message::Telemetry(Telemetry {
+                enabled: body.u8()? != 0,
+                angle: body.f32()?,
+                speed: body.f32()?,
+                left_duty_cycle: body.f32()?,
+                right_duty_cycle: body.f32()?,
+            }),
+            18 => Message::Rejected(String::from_utf8_lossy(body.0).into_owned()),
+            kind => return Err(format!("Unknown message kind {kind}.")),
+        };
+        Ok(Packet { seq, msg })
+    }
+}
+
+impl Message {
+    fn kind(&self) -> u8 {
+        match self {
+            Self::Stop { .. } => 0,
+            Self::Arm { .. } => 1,
+            Self::Teleop { .. } => 2,
+            Self::Subscribe { .. } => 3,
+            Self::Ack => 16,
+            Self::Telemetry(_) => 17,
+            Self::Rejected(_) => 18,
+        }
+    }
+
+    fn token(&self) -> Option<&str> {
+        match self {
+            Self::Stop { token }
+            | Self::Arm { token }
+            | Self::Teleop { token, .. }
+            | Self::Subscribe { token } => Some(token),
+            _ => None,
+        }
+    }
+}
+
+fn encode_token(buf: &mut Vec<u8>, token: &str) {
+    let token = &token.as_bytes()[..token.len().min(u8::MAX as usize)];
+    buf.push(token.len() as u8);
+    buf.extend(token);
+}
+
+/// Reads fields from the body of a packet.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < len {
+            return Err("Packet is too short.".to_string());
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn token(&mut self) -> Result<String, String> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "Token is not UTF-8.".to_string())
+    }
+}
+
+/// Models the [udp] table of the config.
+pub struct UdpConfig {
+    /// Address to listen on.
+    pub address: String,
+    /// Port to listen on.
+    pub port: u16,
+}
+
+/// Keys of the [udp] table.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct UdpSchema {
+    address: Field<String>,
+    port: Field<i64>,
+}
+
+impl UdpConfig {
+    /// Returns None if the config has no [udp] table.
+    pub fn from_toml(path: &str) -> Result<Option<Self>, Vec<ConfigError>> {
+        config::section(path, "udp", |schema: UdpSchema, checker| Self {
+            address: checker
+                .get("udp.address", schema.address)
+                .map_or("0.0.0.0".to_string(), |(address, _)| address),
+            port: checker.int("udp.port", schema.port, 5005, 0..=u16::MAX as i64) as u16,
+        })
+    }
+}
+
+/// Serves the protocol on a background thread.
+/// Commands must carry token, so it is refused if there is none.
+/// Clients share control of the car with the remote through lease.
+pub fn serve<T: Drivable>(
+    car: CarControl<T>,
+    config: UdpConfig,
+    token: Option<String>,
+    lease: LeaseControl,
+) -> io::Result<()> {
+    let Some(token) = token.filter(|token| !token.is_empty()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "[udp] requires a token in [auth], as anyone on the network could drive otherwise",
+        ));
+    };
+    let addr = format!("{}:{}", config.address, config.port);
+    let socket = UdpSocket::bind(&addr)?;
+    log::info!("Serving UDP control on {addr}");
+    let subscribers: Arc<Mutex<HashMap<SocketAddr, Instant>>> = Arc::default();
+
+    let telemetry_socket = socket.try_clone()?;
+    let telemetry_subscribers = subscribers.clone();
+    let telemetry_car = car.clone();
+    thread::spawn(move || {
+        let mut seq = 0u32;
+        loop {
+            thread::sleep(TELEMETRY_INTERVAL);
+            seq = seq.wrapping_add(1);
+            let packet = Packet {
+                seq,
+                msg: Message::Telemetry(Telemetry {
+                    enabled: telemetry_car.is_enabled(),
+                    angle: metrics::ANGLE.get() as f32,
+                    speed: metrics::SPEED.get() as f32,
+                    left_duty_cycle: metrics::LEFT_DUTY_CYCLE.get() as f32,
+                    right_duty_cycle: metrics::RIGHT_DUTY_CYCLE.get() as f32,
+                }),
+            }
+            .encode();
+            let mut subscribers = telemetry_subscribers.lock().unwrap();
+            subscribers.retain(|_, last_seen| last_seen.elapsed() < SUBSCRIBER_TIMEOUT);
+            for addr in subscribers.keys() {
+                let _ = telemetry_socket.send_to(&packet, addr);
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let mut car = car;
+        let mut last_seq: HashMap<SocketAddr, u32> = HashMap::new();
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) => {
+                    log::warn!("Failed to receive UDP packet: {err}");
+                    continue;
+                }
+            };
+            let packet = match Packet::decode(&buf[..len]) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    log::debug!("Ignoring UDP packet from {addr}: {err}");
+                    continue;
+                }
+            };
+
+            let client = format!("udp {addr}");
+            let reply = handle_packet(&mut car, &lease, &client, &packet, &token, |seq| {
+                let fresh = last_seq.get(&addr).map_or(true, |last| seq > *last);
+                if fresh {
+                    last_seq.insert(addr, seq);
+                }
+                fresh
+            });
+            if reply == Some(Message::Ack) {
+                subscribers.lock().unwrap().insert(addr, Instant::now());
+            }
+            if let Some(msg) = reply {
+                let reply = Packet {
+                    seq: packet.seq,
+                    msg,
+                };
+                let _ = socket.send_to(&reply.encode(), addr);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Carries out a command from client.
+/// fresh is called with the sequence number and returns false if it is stale.
+/// Returns the reply to send, or None if the packet should be ignored.
+pub(crate) fn handle_packet<T: Drivable>(
+    car: &mut CarControl<T>,
+    lease: &LeaseControl,
+    client: &str,
+    packet: &Packet,
+    token: &str,
+    fresh: impl FnOnce(u32) -> bool,
+) -> Option<Message> {
+    let presented = packet.msg.token()?;
+    if !tokens_match(token, presented) {
+        return Some(Message::Rejected("Unauthorised".to_string()));
+    }
+
+    // Always obey stop, even out of order.
+    if let Message::Stop { .. } = packet.msg {
+        log::info!("Stopping by UDP...");
+        car.disable();
+        return Some(Message::Ack);
+    }
+    if !fresh(packet.seq) {
+        return None;
+    }
+
+    if let Message::Arm { .. } | Message::Teleop { .. } = packet.msg {
+        if let Claim::Held(holder) = lease.claim(client, false) {
+            return Some(Message::Rejected(format!("Control is held by {holder}")));
+        }
+    }
+    match &packet.msg {
+        Message::Arm { .. } => {
+            log::info!("Arming by UDP...");
+            car.enable();
+        }
+        Message::Teleop { angle, speed, .. } => {
+            if !(-90.0..=90.0).contains(angle) {
+                return Some(Message::Rejected(format!(
+                    "Angle must be between -90 and 90, got {angle}"
+                )));
+            }
+            if !(-100..=100).contains(speed) {
+                return Some(Message::Rejected(format!(
+                    "Speed must be between -100 and 100, got {speed}"
+                )));
+            }
+            if !car.is_enabled() {
+                return Some(Message::Rejected("Car is not armed".to_string()));
+            }
+            car.teleop(*angle, *speed);
+        }
+        Message::Subscribe { .. } => {
+            // Watching the car doesn't keep it alive, only its controller can.
+            if !lease.holder().is_some_and(|(holder, _)| holder == client) {
+                return Some(Message::Ack);
+            }
+        }
+        _ => return None,
+    }
+    car.beat();
+    Some(Message::Ack)
+}
+
+/// Client for controlling a car over the protocol.
+pub struct Client {
+    socket: UdpSocket,
+    seq: u32,
+    token: String,
+}
+
+impl Client {
+    /// Connects to the car at addr, authenticating with token.
+    pub fn connect(addr: impl ToSocketAddrs, token: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(Self {
+            socket,
+            // Start from the time so a restarted client is not treated as stale.
+            seq: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u32,
+            token: token.to_owned(),
+        })
+    }
+
+    fn send(&mut self, msg: Message) -> io::Result<u32> {
+        self.seq = self.seq.wrapping_add(1);
+        self.socket.send(&Packet { seq: self.seq, msg }.encode())?;
+        Ok(self.seq)
+    }
+
+    /// Sends a command and waits for it to be acknowledged.
+    fn command(&mut self, msg: Message) -> io::Result<()> {
+        let seq = self.send(msg)?;
+        loop {
+            match self.recv()? {
+                Packet {
+                    seq: ack_seq,
+                    msg: Message::Ack,
+                } if ack_seq == seq => return Ok(()),
+                Packet {
+                    seq: ack_seq,
+                    msg: Message::Rejected(reason),
+                } if ack_seq == seq => {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    pub fn stop(&mut self) -> io::Result<()> {
+        let token = self.token.clone();
+        self.command(Message::Stop { token })
+    }
+
+    pub fn arm(&mut self) -> io::Result<()> {
+        let token = self.token.clone();
+        self.command(Message::Arm { token })
+    }
+
+    pub fn teleop(&mut self, angle: Angle, speed: Percent) -> io::Result<()> {
+        let token = self.token.clone();
+        self.command(Message::Teleop {
+            token,
+            angle,
+            speed,
+        })
+    }
+
+    /// Asks the car to send telemetry to this client.
+    /// Must be repeated at least every few seconds to keep receiving it.
+    pub fn subscribe(&mut self) -> io::Result<()> {
+        let token = self.token.clone();
+        self.command(Message::Subscribe { token })
+    }
+
+    /// Waits for the next packet from the car.
+    pub fn recv(&self) -> io::Result<Packet> {
+        let mut buf = [0u8; 512];
+        loop {
+            let len = self.socket.recv(&mut buf)?;
+            if let Ok(packet) = Packet::decode(&buf[..len]) {
+                return Ok(packet);
+            }
+        }
+    }
+}